@@ -0,0 +1,59 @@
+//! Spawn latency with a large resident set, which makes `fork` slow as it copies the page tables. Compares [`Spawn`]'s `vfork` path, taken from the main thread on Linux, with its `fork` fallback, taken from other threads, and with `std::process::Command`.
+//!
+//! Run with `cargo bench --bench spawn`. The resident set defaults to 1 GiB, and can be set in MiB with `PALAVER_BENCH_RSS_MIB`.
+
+#[cfg(unix)]
+mod spawn {
+	use palaver::process::{Spawn, WaitStatus};
+	use std::{env, process::Command, thread, time::Instant};
+
+	const ITERATIONS: u32 = 100;
+
+	fn bench<F: FnMut()>(name: &str, mut f: F) {
+		f();
+		let start = Instant::now();
+		for _ in 0..ITERATIONS {
+			f();
+		}
+		println!("{:<32}{:>10.1?}", name, start.elapsed() / ITERATIONS);
+	}
+
+	fn spawn() {
+		let child = Spawn::new("/bin/true").spawn().unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+	}
+
+	pub fn main() {
+		let mib = env::var("PALAVER_BENCH_RSS_MIB").map_or(1024, |mib| mib.parse().unwrap());
+		// touched, so it's resident and its page tables populated
+		let mut memory = vec![0_u8; mib * 1024 * 1024];
+		for page in memory.chunks_mut(4096) {
+			page[0] = 1;
+		}
+		println!("spawning /bin/true with {} MiB resident", mib);
+		bench("Spawn", spawn);
+		// includes spawning the thread
+		bench("Spawn from another thread", || {
+			thread::spawn(spawn).join().unwrap();
+		});
+		bench("std::process::Command", || {
+			assert!(Command::new("/bin/true").status().unwrap().success());
+		});
+		assert_eq!(
+			memory.chunks(4096).filter(|page| page[0] == 1).count(),
+			memory.len() / 4096
+		);
+	}
+}
+#[cfg(windows)]
+mod spawn {
+	pub fn main() {
+		println!("not implemented on windows");
+	}
+}
+fn main() {
+	spawn::main();
+}
@@ -29,13 +29,16 @@
 	clippy::shadow_unrelated,
 	clippy::similar_names,
 	clippy::wildcard_imports,
-	clippy::match_single_binding
+	clippy::match_single_binding,
+	clippy::unnested_or_patterns
 )]
 
 pub mod env;
 mod ext;
 pub mod file;
 pub mod process;
+#[cfg(unix)]
+pub mod rlimit;
 pub mod socket;
 pub mod thread;
 #[cfg(unix)]
@@ -2,57 +2,147 @@
 
 #[cfg(unix)]
 use nix::{
-	errno::Errno, fcntl, libc, sys::{signal, wait}, unistd::{self, Pid}, Error
+	errno::Errno, fcntl, libc, poll, sys::{signal, stat, wait}, unistd::{self, Pid}, Error
 };
+#[cfg(unix)]
+use std::os::unix::process::CommandExt as _;
+#[cfg(unix)]
 use std::process::Command;
 #[cfg(unix)]
 use std::{
-	os::unix::net::UnixDatagram, sync::atomic::{AtomicU8, Ordering}
+	convert::TryInto, ffi::{CString, OsStr, OsString}, fmt, io, iter, mem, os::unix::{
+		ffi::OsStrExt, io::{AsRawFd, IntoRawFd}, net::UnixDatagram
+	}, panic, path::{Path, PathBuf}, ptr, sync::{
+		atomic::{AtomicBool, AtomicI32, AtomicU8, Ordering}, mpsc, Arc, Mutex, PoisonError, RwLock
+	}, thread, time::{Duration, Instant}
+};
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::{
+	fs, time::{SystemTime, UNIX_EPOCH}
 };
 
 #[cfg(unix)]
-use crate::{file, Fd};
+use crate::{file, rlimit, socket, Fd};
 
 #[doc(inline)]
 #[cfg(unix)]
 pub use signal::Signal;
 
-/// Count the number of processes visible to this process. Counts the lines of `ps aux` minus one (the header).
+/// Information about a process, as returned by [`list`].
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Clone, Debug)]
+pub struct ProcessInfo {
+	/// Process ID
+	pub pid: Pid,
+	/// Parent process ID
+	pub ppid: Pid,
+	/// Process group ID
+	pub pgid: Pid,
+	/// Session ID
+	pub sid: Pid,
+	/// Executable name, truncated to 15 bytes
+	pub comm: String,
+	/// State, as one of the characters `RSDZTtWXxKP` described in [`proc(5)`](http://man7.org/linux/man-pages/man5/proc.5.html)
+	pub state: char,
+	/// Number of threads
+	pub threads: usize,
+	/// Time the process started
+	pub start_time: SystemTime,
+}
+
+/// List the processes visible to this process. Uses [`/proc/[pid]/stat`](http://man7.org/linux/man-pages/man5/proc.5.html). Processes that exit while being listed are skipped.
+///
+/// Returns an error if `/proc` isn't mounted or can't be read.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn list() -> io::Result<impl Iterator<Item = ProcessInfo>> {
+	let ticks: u64 = procfs::ticks_per_second()?
+		.try_into()
+		.map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+	let boot_time = UNIX_EPOCH
+		+ Duration::from_secs(procfs::boot_time_secs().map_err(|err| match err {
+			procfs::ProcError::Io(err, _) => err,
+			procfs::ProcError::PermissionDenied(_) => io::ErrorKind::PermissionDenied.into(),
+			procfs::ProcError::NotFound(_) => io::ErrorKind::NotFound.into(),
+			_ => io::ErrorKind::InvalidData.into(),
+		})?);
+	Ok(fs::read_dir("/proc")?
+		.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+		.filter_map(|pid| procfs::process::Process::new(pid).ok())
+		.filter_map(move |process| {
+			let stat = process.stat;
+			Some(ProcessInfo {
+				pid: Pid::from_raw(stat.pid),
+				ppid: Pid::from_raw(stat.ppid),
+				pgid: Pid::from_raw(stat.pgrp),
+				sid: Pid::from_raw(stat.session),
+				comm: stat.comm,
+				state: stat.state,
+				threads: stat.num_threads.try_into().ok()?,
+				start_time: boot_time
+					+ Duration::from_secs(stat.starttime / ticks)
+					+ Duration::from_nanos(stat.starttime % ticks * 1_000_000_000 / ticks),
+			})
+		}))
+}
+
+/// Count the number of processes visible to this process. Uses [`list`] on Linux, and counts the lines of `ps aux` minus one (the header) elsewhere.
+///
+/// # Panics
+///
+/// Panics if `/proc` can't be read on Linux, or `ps` can't be run elsewhere.
 pub fn count() -> usize {
-	let out = Command::new("ps")
-		.arg("aux")
-		.output()
-		.expect("failed to execute process");
-	out.stdout
-		.split(|&x| x == b'\n')
-		.skip(1)
-		.filter(|x| !x.is_empty())
-		.count()
-}
-
-/// Count the number of threads visible to this process. Counts the lines of `ps -eL` and equivalent minus one (the header).
-pub fn count_threads() -> usize {
-	let out = if cfg!(any(target_os = "linux", target_os = "android")) {
-		Command::new("ps")
-			.arg("-eL")
-			.output()
-			.expect("failed to execute process")
-	} else if cfg!(any(target_os = "macos", target_os = "ios")) {
-		Command::new("ps")
-			.arg("-eM")
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	{
+		list().expect("failed to read /proc").count()
+	}
+	#[cfg(not(any(target_os = "android", target_os = "linux")))]
+	{
+		let out = Command::new("ps")
+			.arg("aux")
 			.output()
-			.expect("failed to execute process")
-	} else {
-		unimplemented!()
-	};
-	out.stdout
-		.split(|&x| x == b'\n')
-		.skip(1)
-		.filter(|x| !x.is_empty())
-		.count()
+			.expect("failed to execute process");
+		out.stdout
+			.split(|&x| x == b'\n')
+			.skip(1)
+			.filter(|x| !x.is_empty())
+			.count()
+	}
+}
+
+/// Count the number of threads visible to this process. Sums the thread counts from [`list`] on Linux, and counts the lines of `ps -eM` minus one (the header) on macOS.
+///
+/// # Panics
+///
+/// Panics if `/proc` can't be read on Linux, or `ps` can't be run on macOS.
+pub fn count_threads() -> usize {
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	{
+		list()
+			.expect("failed to read /proc")
+			.map(|process| process.threads)
+			.sum()
+	}
+	#[cfg(not(any(target_os = "android", target_os = "linux")))]
+	{
+		let out = if cfg!(any(target_os = "macos", target_os = "ios")) {
+			Command::new("ps")
+				.arg("-eM")
+				.output()
+				.expect("failed to execute process")
+		} else {
+			unimplemented!()
+		};
+		out.stdout
+			.split(|&x| x == b'\n')
+			.skip(1)
+			.filter(|x| !x.is_empty())
+			.count()
+	}
 }
 
 /// Child process handle
+///
+/// This is `Sync`, so can be shared between threads, for example in an `Arc`. Any number of threads can wait on it at once: they all get the same status, and once the child's been reaped [`signal`](ChildHandle::signal) returns `ESRCH`.
 #[cfg(unix)]
 #[derive(Debug)]
 pub struct ChildHandle {
@@ -62,14 +152,22 @@ pub struct ChildHandle {
 	#[cfg(target_os = "freebsd")]
 	pub pd: Fd,
 	owns: Option<Handle>,
+	tracked: children::Tracked,
+	status: Mutex<Option<(WaitStatus, ResourceUsage)>>, // locked to reap, and caches the result for other waiters
+	received: bool, // from another process by `receive`, so likely not ours to reap
 }
 
 #[cfg(unix)]
 #[derive(Debug)]
 struct Handle {
-	state: AtomicU8, // 0, 1 = killed, 2 = reaped
+	state: AtomicU8,             // 0, 1 = killed, 2 = reaped
+	retainer_killed: AtomicBool, // by `kill_tree`, along with the rest of the tree, after which the group `pid` is no longer ours to kill
+	#[cfg(not(target_os = "freebsd"))]
+	guard_write: Option<Fd>,
+	#[cfg(target_os = "linux")]
+	pidfd: Option<Fd>,
 	#[cfg(not(target_os = "freebsd"))]
-	guard_write: Fd,
+	exit_pipe: Mutex<Option<(Fd, thread::JoinHandle<()>)>>,
 }
 
 /// Possible return values from [`ChildHandle::wait`].
@@ -87,31 +185,401 @@ pub enum WaitStatus {
 	Signaled(Signal, bool),
 }
 
+/// Events returned by [`ChildHandle::wait_events`].
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug)]
+pub enum WaitEvent {
+	/// The process exited normally (as with `exit()` or returning from
+	/// `main`) with the given exit code. This case matches the C macro
+	/// `WIFEXITED(status)`; the field is `WEXITSTATUS(status)`.
+	Exited(i32),
+	/// The process was killed by the given signal. The second field
+	/// indicates whether the signal generated a core dump. This case
+	/// matches the C macro `WIFSIGNALED(status)`; the fields
+	/// correspond to `WTERMSIG(status)` and `WCOREDUMP(status)`.
+	Signaled(Signal, bool),
+	/// The process is alive, but was stopped by the given signal. This
+	/// case matches the C macro `WIFSTOPPED(status)`; the field is
+	/// `WSTOPSIG(status)`.
+	Stopped(Signal),
+	/// The process was previously stopped but has resumed execution
+	/// after receiving a `SIGCONT` signal. This case matches the C macro
+	/// `WIFCONTINUED(status)`.
+	Continued,
+}
+#[cfg(unix)]
+impl WaitStatus {
+	/// The terminal statuses, i.e. not stops or continues.
+	fn from_raw(status: wait::WaitStatus) -> Option<Self> {
+		match status {
+			wait::WaitStatus::Exited(_, code) => Some(Self::Exited(code)),
+			wait::WaitStatus::Signaled(_, signal, dumped) => Some(Self::Signaled(signal, dumped)),
+			_ => None,
+		}
+	}
+}
+
+#[cfg(unix)]
+impl From<WaitStatus> for WaitEvent {
+	fn from(status: WaitStatus) -> Self {
+		match status {
+			WaitStatus::Exited(code) => Self::Exited(code),
+			WaitStatus::Signaled(signal, dumped) => Self::Signaled(signal, dumped),
+		}
+	}
+}
+
+/// Iterator returned by [`ChildHandle::wait_events`].
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct WaitEvents<'a> {
+	child: &'a ChildHandle,
+	done: bool,
+}
+#[cfg(unix)]
+impl Iterator for WaitEvents<'_> {
+	type Item = nix::Result<WaitEvent>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+		let ret = self.child.wait_event();
+		self.done = !matches!(ret, Ok(WaitEvent::Stopped(_)) | Ok(WaitEvent::Continued));
+		Some(ret)
+	}
+}
+
+/// The resources used by a child process, as returned by [`ChildHandle::wait_with_rusage`]. Akin to `struct rusage`.
+#[cfg(unix)]
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ResourceUsage {
+	/// Time spent executing in user mode
+	pub user_time: Duration,
+	/// Time spent executing in kernel mode
+	pub system_time: Duration,
+	/// Maximum resident set size, in bytes
+	pub max_rss: u64,
+	/// Page faults serviced without any I/O
+	pub minor_faults: u64,
+	/// Page faults that required I/O
+	pub major_faults: u64,
+	/// Context switches due to the process blocking, for example on I/O
+	pub voluntary_context_switches: u64,
+	/// Context switches due to preemption
+	pub involuntary_context_switches: u64,
+}
+#[cfg(unix)]
+impl ResourceUsage {
+	fn from_rusage(rusage: libc::rusage) -> Self {
+		fn duration(time: libc::timeval) -> Duration {
+			Duration::new(
+				time.tv_sec.try_into().unwrap(),
+				(time.tv_usec * 1000).try_into().unwrap(),
+			)
+		}
+		fn count(count: libc::c_long) -> u64 {
+			count.try_into().unwrap()
+		}
+		// ru_maxrss is in bytes on macOS and iOS, kilobytes elsewhere
+		let max_rss_unit = if cfg!(any(target_os = "macos", target_os = "ios")) {
+			1
+		} else {
+			1024
+		};
+		Self {
+			user_time: duration(rusage.ru_utime),
+			system_time: duration(rusage.ru_stime),
+			max_rss: count(rusage.ru_maxrss) * max_rss_unit,
+			minor_faults: count(rusage.ru_minflt),
+			major_faults: count(rusage.ru_majflt),
+			voluntary_context_switches: count(rusage.ru_nvcsw),
+			involuntary_context_switches: count(rusage.ru_nivcsw),
+		}
+	}
+}
+
 #[cfg(unix)]
 impl ChildHandle {
-	/// Signal the child process
-	// TODO: catch multiple waiters
+	/// Wait for the child process to exit. If it's already been reaped, as by another thread waiting on it, this returns the same status.
 	pub fn wait(&self) -> nix::Result<WaitStatus> {
-		let ret = Self::wait_(self.pid);
-		if let (Ok(_), Some(owns)) = (ret, &self.owns) {
+		self.wait_with_rusage().map(|(status, _)| status)
+	}
+	/// Wait for the child process to exit, additionally returning the resources it used. Uses `wait4`.
+	pub fn wait_with_rusage(&self) -> nix::Result<(WaitStatus, ResourceUsage)> {
+		loop {
+			if let Some(ret) = self.try_wait_with_rusage()? {
+				break Ok(ret);
+			}
+			if !self.wait_exit()? {
+				// there's no waiting without reaping, so reap holding the lock
+				let mut status = self.status.lock().unwrap();
+				if status.is_none() {
+					*status = Self::wait4_(self.pid, None)?;
+					self.set_reaped();
+				}
+				break Ok(status.unwrap());
+			}
+		}
+	}
+	/// Block till the child has exited, without reaping it. Returns `false` if this can't be done without racing against other waiters reaping it, in which case the pid could be reused.
+	fn wait_exit(&self) -> nix::Result<bool> {
+		if let Some(fd) = self.exit_fd_() {
+			let mut fds = [poll::PollFd::new(fd, poll::PollFlags::POLLIN)];
+			match poll::poll(&mut fds, -1) {
+				Ok(_) | Err(Error::Sys(Errno::EINTR)) => Ok(true),
+				Err(err) => Err(err),
+			}
+		} else if self.received {
+			// there's nothing to block on for a process that might not be our child
+			thread::sleep(Duration::from_millis(10));
+			Ok(true)
+		} else if self.owns.is_some() {
+			// the pid retainer stops the pid being reused once reaped, so if another waiter gets there first this returns ECHILD rather than waiting on someone else
+			let mut info = mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+			let ret = unsafe {
+				libc::waitid(
+					libc::P_PID,
+					self.pid.as_raw().try_into().unwrap(),
+					info.as_mut_ptr(),
+					libc::WEXITED | libc::WNOWAIT,
+				)
+			};
+			match Errno::result(ret) {
+				Ok(_) | Err(Error::Sys(Errno::EINTR)) | Err(Error::Sys(Errno::ECHILD)) => Ok(true),
+				Err(err) => Err(err),
+			}
+		} else {
+			Ok(false)
+		}
+	}
+	/// Iterate the child process's state changes: stopping (as by `SIGSTOP` or `SIGTSTP`), continuing (`SIGCONT`), and finally exiting, after which the iterator ends. Each call to `next()` blocks till the next event. Uses `WUNTRACED` and `WCONTINUED`.
+	///
+	/// This is opt-in: [`wait`](ChildHandle::wait) and friends skip over stops and continues, and only return once the child is dead.
+	pub fn wait_events(&self) -> WaitEvents<'_> {
+		WaitEvents {
+			child: self,
+			done: false,
+		}
+	}
+	fn wait_event(&self) -> nix::Result<WaitEvent> {
+		let mut flags = wait::WaitPidFlag::WUNTRACED | wait::WaitPidFlag::WCONTINUED;
+		loop {
+			if self.owns.is_some() {
+				// block without the lock till there's an event, leaving it to be consumed below; the pid retainer stops the pid being reused if another waiter reaps it meanwhile
+				if let Some((status, _)) = *self.status.lock().unwrap() {
+					return Ok(status.into());
+				}
+				let mut info = mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+				let ret = unsafe {
+					libc::waitid(
+						libc::P_PID,
+						self.pid.as_raw().try_into().unwrap(),
+						info.as_mut_ptr(),
+						libc::WEXITED | libc::WSTOPPED | libc::WCONTINUED | libc::WNOWAIT,
+					)
+				};
+				match Errno::result(ret) {
+					Ok(_) | Err(Error::Sys(Errno::EINTR)) | Err(Error::Sys(Errno::ECHILD)) => (),
+					Err(err) => return Err(err),
+				}
+				flags |= wait::WaitPidFlag::WNOHANG;
+			}
+			let mut status = self.status.lock().unwrap();
+			if let Some((status, _)) = *status {
+				return Ok(status.into());
+			}
+			match Self::wait4_raw(self.pid, flags)? {
+				Some((wait::WaitStatus::Stopped(_, signal), _)) => {
+					return Ok(WaitEvent::Stopped(signal))
+				}
+				Some((wait::WaitStatus::Continued(_), _)) => return Ok(WaitEvent::Continued),
+				Some((raw, rusage)) => {
+					if let Some(status_) = WaitStatus::from_raw(raw) {
+						*status = Some((status_, rusage));
+						self.set_reaped();
+						return Ok(status_.into());
+					}
+				}
+				None => (),
+			}
+		}
+	}
+	/// Check whether the child process has exited, without blocking. Returns `Ok(None)` if it's still running. If it's already been reaped, as by another thread waiting on it, this returns the same status.
+	pub fn try_wait(&self) -> nix::Result<Option<WaitStatus>> {
+		self.try_wait_with_rusage()
+			.map(|ret| ret.map(|(status, _)| status))
+	}
+	fn try_wait_with_rusage(&self) -> nix::Result<Option<(WaitStatus, ResourceUsage)>> {
+		let mut status = self.status.lock().unwrap();
+		if status.is_none() {
+			*status = match Self::wait4_(self.pid, Some(wait::WaitPidFlag::WNOHANG)) {
+				// not our child, so all we can tell is whether it's exited
+				Err(Error::Sys(Errno::ECHILD)) if self.received => {
+					if !self.exited()? {
+						return Ok(None);
+					}
+					self.set_reaped();
+					return Err(Error::Sys(Errno::ECHILD));
+				}
+				ret => ret?,
+			};
+			if status.is_some() {
+				self.set_reaped();
+			}
+		}
+		Ok(*status)
+	}
+	/// Wait for the child process to exit, giving up after `timeout`. Returns `Ok(None)` if it's still running.
+	///
+	/// This polls the pidfd on Linux and the process descriptor on FreeBSD; elsewhere it falls back to polling [`try_wait`](ChildHandle::try_wait) with backoff.
+	pub fn wait_timeout(&self, timeout: Duration) -> nix::Result<Option<WaitStatus>> {
+		let deadline = Instant::now() + timeout;
+		let mut backoff = Duration::from_millis(1);
+		loop {
+			if let Some(status) = self.try_wait()? {
+				break Ok(Some(status));
+			}
+			let now = Instant::now();
+			if now >= deadline {
+				break Ok(None);
+			}
+			let remaining = deadline - now;
+			if let Some(fd) = self.exit_fd_() {
+				let timeout = remaining
+					.as_millis()
+					.saturating_add(1)
+					.try_into()
+					.unwrap_or(libc::c_int::MAX);
+				let mut fds = [poll::PollFd::new(fd, poll::PollFlags::POLLIN)];
+				match poll::poll(&mut fds, timeout) {
+					Ok(_) | Err(Error::Sys(Errno::EINTR)) => (),
+					Err(err) => break Err(err),
+				}
+			} else {
+				thread::sleep(backoff.min(remaining));
+				backoff = (backoff * 2).min(Duration::from_millis(100));
+			}
+		}
+	}
+	/// Whether a child that's not ours to reap has exited: its exit fd polls readable, or else its pid has gone, which the pid retainer stops being reused.
+	fn exited(&self) -> nix::Result<bool> {
+		if let Some(fd) = self.exit_fd_() {
+			let mut fds = [poll::PollFd::new(fd, poll::PollFlags::POLLIN)];
+			match poll::poll(&mut fds, 0) {
+				Ok(ready) => Ok(ready != 0),
+				Err(Error::Sys(Errno::EINTR)) => Ok(false),
+				Err(err) => Err(err),
+			}
+		} else {
+			match signal::kill(self.pid, None) {
+				Ok(()) => Ok(false),
+				Err(Error::Sys(Errno::ESRCH)) => Ok(true),
+				Err(err) => Err(err),
+			}
+		}
+	}
+	/// A file descriptor that polls readable (`POLLIN`) or hung up (`POLLHUP`) once the child process has exited, for use with `poll`, `epoll`, `kqueue`, mio, tokio etc. The child still needs to be reaped with [`wait`](ChildHandle::wait) or [`try_wait`](ChildHandle::try_wait).
+	///
+	/// This is the pidfd on Linux 5.3+, and the process descriptor on FreeBSD. Elsewhere it's the read end of a pipe that a helper thread, blocked in `waitid(WNOWAIT)`, closes once the child exits; the thread is spawned the first time this is called. For a [`receive`](ChildHandle::receive)d child that isn't ours the thread instead polls with `kill(pid, 0)` every 10ms, and gives up when this handle's dropped.
+	///
+	/// The file descriptor is owned by the `ChildHandle` and closed when it's dropped. Fails with `EINVAL` for orphans, other than on FreeBSD, or with the error from creating the pipe or the thread.
+	pub fn exit_fd(&self) -> io::Result<Fd> {
+		if let Some(fd) = self.exit_fd_() {
+			return Ok(fd);
+		}
+		#[cfg(not(target_os = "freebsd"))]
+		{
+			let owns = self
+				.owns
+				.as_ref()
+				.ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+			let mut exit_pipe = owns
+				.exit_pipe
+				.lock()
+				.unwrap_or_else(PoisonError::into_inner);
+			if let Some((read, _)) = &*exit_pipe {
+				return Ok(*read);
+			}
+			let (read, thread) = exit_pipe_(self.pid, self.received)?;
+			*exit_pipe = Some((read, thread));
+			Ok(read)
+		}
+		#[cfg(target_os = "freebsd")]
+		Err(io::Error::from_raw_os_error(libc::EINVAL))
+	}
+	/// An fd that polls readable (or hung up) once the child has exited, where the platform gives us one.
+	fn exit_fd_(&self) -> Option<Fd> {
+		#[cfg(target_os = "freebsd")]
+		{
+			if self.pd != i32::max_value() {
+				return Some(self.pd);
+			}
+		}
+		#[cfg(target_os = "linux")]
+		{
+			if let Some(pidfd) = self.owns.as_ref().and_then(|owns| owns.pidfd) {
+				return Some(pidfd);
+			}
+		}
+		None
+	}
+	fn set_reaped(&self) {
+		if let Some(owns) = &self.owns {
 			owns.state.store(2, Ordering::Relaxed);
 		}
-		ret
+		children::remove(&self.tracked);
 	}
 	fn wait_(pid: Pid) -> nix::Result<WaitStatus> {
-		// EVFILT_PROCDESC on freebsd?
-		// pidfd linux? https://lwn.net/Articles/784831/ https://lwn.net/Articles/794707/ https://github.com/pop-os/pidfd
+		Self::wait4_(pid, None).map(|ret| ret.unwrap().0)
+	}
+	fn try_wait_(pid: Pid) -> nix::Result<Option<WaitStatus>> {
+		Self::wait4_(pid, Some(wait::WaitPidFlag::WNOHANG)).map(|ret| ret.map(|ret| ret.0))
+	}
+	/// `wait4`, skipping over stop and continue events. Returns `None` only if `flags` contains `WNOHANG` and the child hasn't exited.
+	fn wait4_(
+		pid: Pid, flags: Option<wait::WaitPidFlag>,
+	) -> nix::Result<Option<(WaitStatus, ResourceUsage)>> {
+		let flags = flags.unwrap_or_else(wait::WaitPidFlag::empty);
 		loop {
-			match wait::waitpid(pid, None) {
-				Ok(wait::WaitStatus::Exited(pid_, code)) => {
-					assert_eq!(pid_, pid);
-					break Ok(WaitStatus::Exited(code));
+			match Self::wait4_raw(pid, flags)? {
+				Some((raw, rusage)) => {
+					if let Some(status) = WaitStatus::from_raw(raw) {
+						break Ok(Some((status, rusage)));
+					}
+					if flags.contains(wait::WaitPidFlag::WNOHANG) {
+						break Ok(None);
+					}
 				}
-				Ok(wait::WaitStatus::Signaled(pid_, signal, dumped)) => {
-					assert_eq!(pid_, pid);
-					break Ok(WaitStatus::Signaled(signal, dumped));
+				None => break Ok(None),
+			}
+		}
+	}
+	/// `wait4`, retrying on `EINTR`. Returns `None` only if `flags` contains `WNOHANG` and there's nothing to report.
+	fn wait4_raw(
+		pid: Pid, flags: wait::WaitPidFlag,
+	) -> nix::Result<Option<(wait::WaitStatus, ResourceUsage)>> {
+		// EVFILT_PROCDESC on freebsd?
+		loop {
+			let mut status = 0;
+			let mut rusage = mem::MaybeUninit::<libc::rusage>::zeroed();
+			let ret = unsafe {
+				libc::wait4(
+					pid.as_raw(),
+					ptr::NonNull::from(&mut status).as_ptr(),
+					flags.bits(),
+					rusage.as_mut_ptr(),
+				)
+			};
+			match Errno::result(ret) {
+				Ok(0) => break Ok(None),
+				Ok(pid_) => {
+					assert_eq!(pid_, pid.as_raw());
+					let status = wait::WaitStatus::from_raw(pid, status)?;
+					let rusage = ResourceUsage::from_rusage(unsafe { rusage.assume_init() });
+					break Ok(Some((status, rusage)));
 				}
-				Ok(_) | Err(Error::Sys(Errno::EINTR)) => (),
+				Err(Error::Sys(Errno::EINTR)) => (),
 				Err(err) => break Err(err),
 			}
 		}
@@ -122,7 +590,6 @@ impl ChildHandle {
 		let signal = signal.into();
 		#[cfg(target_os = "freebsd")]
 		{
-			assert_ne!(self.pd, i32::max_value(), "todo");
 			let res = unsafe {
 				libc::pdkill(
 					self.pd,
@@ -141,72 +608,955 @@ impl ChildHandle {
 		if owns.state.load(Ordering::Relaxed) != 0 {
 			return Err(Error::Sys(Errno::ESRCH));
 		}
+		#[cfg(target_os = "linux")]
+		{
+			if let Some(pidfd) = owns.pidfd {
+				pidfd::send_signal(pidfd, signal)?;
+			} else {
+				signal::kill(self.pid, signal)?;
+			}
+		}
+		#[cfg(not(target_os = "linux"))]
 		signal::kill(self.pid, signal)?;
 		if signal == Some(signal::SIGKILL) {
 			let _ = owns.state.compare_and_swap(0, 1, Ordering::Relaxed);
 		}
 		Ok(())
 	}
-}
-
-#[cfg(unix)]
-impl Drop for ChildHandle {
-	fn drop(&mut self) {
-		if self.owns.is_some() {
-			let state = *self.owns.as_mut().unwrap().state.get_mut();
-			if state == 0 {
-				let _ = self.signal(signal::SIGKILL);
+	/// Signal the child process's process group, as with `killpg`. The group is looked up at the time of the call; if the child hasn't moved to a group of its own with `setpgid` or `setsid` it's still in the caller's group, and this returns `EPERM` rather than signalling the caller.
+	///
+	/// Once the child has been reaped this returns `ESRCH`. Where pidfds aren't available the pid retainer keeps the group from being reused while the handle is alive; with a pidfd, a reap racing with this call on another thread could in principle let the group be reused before it's signalled.
+	///
+	/// # Panics
+	///
+	/// Panics if the child is orphaned, except on FreeBSD.
+	pub fn signal_group<T: Into<Option<Signal>>>(&self, signal: T) -> nix::Result<()> {
+		let signal = signal.into();
+		#[cfg(not(target_os = "freebsd"))]
+		let owns = self
+			.owns
+			.as_ref()
+			.expect(".signal_group() can only be called on non-orphaned children");
+		// an unreaped child, even a zombie, keeps its group alive
+		if let Some(owns) = &self.owns {
+			if owns.state.load(Ordering::Relaxed) == 2 {
+				return Err(Error::Sys(Errno::ESRCH));
 			}
-			if state != 2 {
-				let _ = self.wait().unwrap();
+		}
+		let group = unistd::getpgid(Some(self.pid))?;
+		if group == unistd::getpgrp() {
+			return Err(Error::Sys(Errno::EPERM));
+		}
+		signal::killpg(group, signal)?;
+		#[cfg(not(target_os = "freebsd"))]
+		{
+			if signal == Some(signal::SIGKILL) {
+				let _ = owns
+					.state
+					.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed);
 			}
-			let group = Pid::from_raw(-self.pid.as_raw());
-			let _ = signal::kill(group, signal::SIGKILL);
-			#[cfg(not(target_os = "freebsd"))]
-			unistd::close(self.owns.as_mut().unwrap().guard_write).unwrap();
 		}
+		Ok(())
+	}
+	/// Queue a signal along with an integer payload to the child process, as with `sigqueue`. The child can retrieve the payload from the `si_value` field of the `siginfo_t` passed to an `SA_SIGINFO` handler or returned by `sigwaitinfo`. Linux, Android, FreeBSD and NetBSD only.
+	///
+	/// `signal` is a number rather than a [`Signal`] so that real-time signals, `SIGRTMIN()` to `SIGRTMAX()`, can be sent; these are queued rather than merged if sent more than once before being handled. As with [`signal`](ChildHandle::signal), the pid can't have been reused.
+	///
+	/// # Panics
+	///
+	/// Panics if the child is orphaned, except on FreeBSD.
+	#[cfg(any(
+		target_os = "android",
+		target_os = "freebsd",
+		target_os = "linux",
+		target_os = "netbsd"
+	))]
+	pub fn sigqueue(&self, signal: libc::c_int, value: libc::c_int) -> nix::Result<()> {
+		#[cfg(not(target_os = "freebsd"))]
+		{
+			let owns = self
+				.owns
+				.as_ref()
+				.expect(".sigqueue() can only be called on non-orphaned children");
+			if owns.state.load(Ordering::Relaxed) != 0 {
+				return Err(Error::Sys(Errno::ESRCH));
+			}
+			#[cfg(target_os = "linux")]
+			{
+				if let Some(pidfd) = owns.pidfd {
+					return pidfd::queue_signal(pidfd, signal, value);
+				}
+			}
+		}
+		// the pid is reserved by the process descriptor on FreeBSD, and the pid retainer elsewhere
+		let value = unsafe { mem::transmute::<SigVal, libc::sigval>(SigVal { int: value }) };
+		let ret = unsafe { libc::sigqueue(self.pid.as_raw(), signal, value) };
+		Errno::result(ret).map(drop)
+	}
+	/// SIGKILL the child process and all of its descendants, including those that have left its process group with `setsid` or `setpgid`. Linux and Android only.
+	///
+	/// The tree is frozen from the top down with `SIGSTOP`, walking `/proc` for the children of stopped processes till no more are found, and then killed. A stopped process can't reap its children, so none of the pids can be reused from under us. To catch descendants whose parent exits before it's stopped, this process is made a child subreaper (`PR_SET_CHILD_SUBREAPER`) so that they're re-parented to us rather than to init; they're stopped, killed and reaped too.
+	///
+	/// We're only a subreaper while this runs: unless we already were one, or a [`Subreaper`] has since been created, the previous setting is restored before it returns. While it runs, orphaned descendants of other children are re-parented to us too, and children spawned other than with [`fork`] by other threads may be mistaken for descendants of this one.
+	///
+	/// The child itself is left to be reaped by [`wait`](ChildHandle::wait) or on drop.
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	pub fn kill_tree(&self) -> nix::Result<()> {
+		{
+			let mut subreaper = SUBREAPER.lock().unwrap();
+			if subreaper.0 == 0 {
+				subreaper.1 |= is_child_subreaper()?;
+			}
+			set_child_subreaper(true)?;
+			subreaper.0 += 1;
+		}
+		let ret = self.kill_tree_();
+		let mut subreaper = SUBREAPER.lock().unwrap();
+		subreaper.0 -= 1;
+		if subreaper.0 == 0 && !subreaper.1 {
+			set_child_subreaper(false)?;
+		}
+		ret
+	}
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	fn kill_tree_(&self) -> nix::Result<()> {
+		let us = unistd::getpid();
+		let siblings = parents()?
+			.into_iter()
+			.filter(|&(_, ppid)| ppid == us)
+			.map(|(pid, _)| pid)
+			.collect::<Vec<_>>();
+		self.signal(signal::SIGSTOP)?;
+		let mut tree = vec![self.pid];
+		loop {
+			let mut found = false;
+			for (pid, ppid) in parents()? {
+				if tree.contains(&pid) || pid == us {
+					continue;
+				}
+				let straggler = ppid == us && !siblings.contains(&pid) && children::is_foreign(pid);
+				if tree.contains(&ppid) || straggler {
+					let _ = signal::kill(pid, signal::SIGSTOP);
+					tree.push(pid);
+					found = true;
+				}
+			}
+			if !found {
+				break;
+			}
+		}
+		// without a pidfd the tree includes the pid retainer
+		if let Some(owns) = &self.owns {
+			owns.retainer_killed.store(true, Ordering::Relaxed);
+		}
+		for &pid in &tree[1..] {
+			let _ = signal::kill(pid, signal::SIGKILL);
+		}
+		self.signal(signal::SIGKILL)?;
+		// once their parents have exited, descendants are re-parented to us
+		for &pid in &tree[1..] {
+			loop {
+				match wait::waitpid(pid, Some(wait::WaitPidFlag::__WALL)) {
+					Ok(wait::WaitStatus::Exited(..)) | Ok(wait::WaitStatus::Signaled(..)) => break,
+					Err(Error::Sys(Errno::ECHILD)) => {
+						match procfs::process::Process::new(pid.as_raw()) {
+							Ok(process) if process.stat.ppid != us.as_raw() => {
+								thread::sleep(Duration::from_millis(1));
+							}
+							Ok(_) => (),
+							Err(_) => break, // reaped by its parent before it was killed
+						}
+					}
+					Ok(_) | Err(Error::Sys(Errno::EINTR)) => (),
+					Err(err) => return Err(err),
+				}
+			}
+		}
+		Ok(())
+	}
+	/// Send this handle over the Unix socket `sock` with `SCM_RIGHTS`, for [`receive`](ChildHandle::receive) to rebuild in another process, for example a replacement for this one. Along with the pid goes the pidfd on Linux 5.3+, the process descriptor on FreeBSD, and the fd whose closing by all that hold it kills the child where there's one, i.e. other than on FreeBSD and for children tied to us by `PR_SET_PDEATHSIG`.
+	///
+	/// We still kill the child when this handle's dropped, so to hand it over we should exit, or `mem::forget` the handle, rather than drop it. A child forked by `fork(false)` on the main thread on Linux is killed when we exit too; to have it outlive us, fork it with [`ForkOptions::transferable`].
+	///
+	/// Fails with `EINVAL` for orphans other than on FreeBSD, and `ESRCH` once the child's been reaped.
+	pub fn send(&self, sock: Fd) -> nix::Result<()> {
+		let mut fds = Vec::with_capacity(2);
+		let mut flags = 0;
 		#[cfg(target_os = "freebsd")]
 		{
 			if self.pd != i32::max_value() {
-				unistd::close(self.pd).unwrap();
+				fds.push(self.pd);
+				flags |= SEND_PD;
+			}
+		}
+		if let Some(owns) = &self.owns {
+			if owns.state.load(Ordering::Relaxed) == 2 {
+				return Err(Error::Sys(Errno::ESRCH));
+			}
+			#[cfg(target_os = "linux")]
+			{
+				if let Some(pidfd) = owns.pidfd {
+					fds.push(pidfd);
+					flags |= SEND_PIDFD;
+				}
+			}
+			#[cfg(not(target_os = "freebsd"))]
+			{
+				if let Some(guard_write) = owns.guard_write {
+					fds.push(guard_write);
+					flags |= SEND_GUARD;
+				}
+			}
+		}
+		if let Ok(status) = self.status.lock() {
+			if status.is_some() {
+				return Err(Error::Sys(Errno::ESRCH));
+			}
+		}
+		if fds.is_empty() {
+			return Err(Error::Sys(Errno::EINVAL));
+		}
+		let mut msg = [0; 5];
+		msg[..4].copy_from_slice(&self.pid.as_raw().to_ne_bytes());
+		msg[4] = flags;
+		let sent = socket::send_fds(sock, &msg, &fds, false)?;
+		if sent != msg.len() {
+			return Err(Error::Sys(Errno::EMSGSIZE));
+		}
+		Ok(())
+	}
+	/// Receive a handle sent by [`send`](ChildHandle::send) over the Unix socket `sock`.
+	///
+	/// We're not the child's parent, but can still signal it and poll [`as_raw_fd`](AsRawFd::as_raw_fd) for its exit without racing against its pid being reused. Its exit status goes to its parent though, so [`wait`](ChildHandle::wait) and friends return `ECHILD` once it's exited, unless it's been reparented to us, as when we're a child subreaper and its parent has exited, in which case they reap it as usual; [`wait_events`](ChildHandle::wait_events) only works in that case. With a pidfd or process descriptor, waiting blocks in `poll` on it. Without one there's nothing to block on, so waiting polls with `kill(pid, 0)` every 10ms, and only sees the exit once the child's been reaped by its parent.
+	///
+	/// As with the sender's handle, the child is killed when this is dropped, though it isn't waited on. Fails with `EBADMSG` if what's received isn't a handle.
+	pub fn receive(sock: Fd) -> nix::Result<Self> {
+		let mut msg = [0; 5];
+		let mut fds = [-1; 2];
+		let received = socket::recv_fds(sock, &mut msg, &mut fds)?;
+		let fds = &fds[..received.fds];
+		let flags = msg[4];
+		if received.len != msg.len()
+			|| flags & !SEND_ALL != 0
+			|| fds.len() != flags.count_ones() as usize
+		{
+			for &fd in fds {
+				let _ = unistd::close(fd);
 			}
+			return Err(Error::Sys(Errno::EBADMSG));
 		}
+		let mut fds = fds.iter().copied();
+		let mut take = |flag| if flags & flag != 0 { fds.next() } else { None };
+		#[cfg(target_os = "freebsd")]
+		let pd = take(SEND_PD).unwrap_or(i32::max_value());
+		#[cfg(target_os = "linux")]
+		let pidfd = take(SEND_PIDFD);
+		#[cfg(not(target_os = "freebsd"))]
+		let guard_write = take(SEND_GUARD);
+		Ok(Self {
+			pid: Pid::from_raw(i32::from_ne_bytes([msg[0], msg[1], msg[2], msg[3]])),
+			#[cfg(target_os = "freebsd")]
+			pd,
+			#[cfg(not(target_os = "freebsd"))]
+			owns: Some(Handle {
+				state: AtomicU8::new(0),
+				retainer_killed: AtomicBool::new(false),
+				guard_write,
+				#[cfg(target_os = "linux")]
+				pidfd,
+				exit_pipe: Mutex::new(None),
+			}),
+			#[cfg(target_os = "freebsd")]
+			owns: None,
+			tracked: children::Tracked::default(),
+			status: Mutex::new(None),
+			received: true,
+		})
 	}
 }
 
-/// Fork result
-#[cfg(unix)]
-#[derive(Debug)]
-pub enum ForkResult {
-	/// Parent process
-	Parent(ChildHandle),
-	/// Child process
-	Child,
+/// What [`ChildHandle::send`] sends alongside the pid.
+#[cfg(target_os = "linux")]
+const SEND_PIDFD: u8 = 1;
+#[cfg(not(target_os = "freebsd"))]
+const SEND_GUARD: u8 = 2;
+#[cfg(target_os = "freebsd")]
+const SEND_PD: u8 = 4;
+#[cfg(target_os = "linux")]
+const SEND_ALL: u8 = SEND_PIDFD | SEND_GUARD;
+#[cfg(target_os = "freebsd")]
+const SEND_ALL: u8 = SEND_PD;
+#[cfg(not(any(target_os = "freebsd", target_os = "linux")))]
+const SEND_ALL: u8 = SEND_GUARD;
+
+/// `union sigval`, which libc only exposes the pointer member of.
+#[cfg(any(
+	target_os = "android",
+	target_os = "freebsd",
+	target_os = "linux",
+	target_os = "netbsd"
+))]
+#[repr(C)]
+#[derive(Clone, Copy)]
+union SigVal {
+	int: libc::c_int,
+	ptr: *mut libc::c_void,
 }
 
-/// A Rust fork wrapper that provides more coherent, FreeBSD-inspired semantics.
+/// The pids and parent pids of the processes visible to this process, from `/proc`. Processes that exit while being listed are skipped.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn parents() -> nix::Result<Vec<(Pid, Pid)>> {
+	Ok(fs::read_dir("/proc")
+		.map_err(|err| Error::Sys(io_errno(&err)))?
+		.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+		.filter_map(|pid| procfs::process::Process::new(pid).ok())
+		.map(|process| {
+			(
+				Pid::from_raw(process.stat.pid),
+				Pid::from_raw(process.stat.ppid),
+			)
+		})
+		.collect())
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+lazy_static::lazy_static! {
+	/// The number of [`ChildHandle::kill_tree`]s running, and whether we're to remain a child subreaper once they're done.
+	static ref SUBREAPER: Mutex<(usize, bool)> = Mutex::new((0, false));
+}
+
+/// Make this process a child subreaper, i.e. have orphaned descendants re-parented to it rather than to init, or stop it being one.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn set_child_subreaper(subreaper: bool) -> nix::Result<()> {
+	let ret = unsafe {
+		libc::prctl(
+			libc::PR_SET_CHILD_SUBREAPER,
+			libc::c_ulong::from(subreaper),
+			0,
+			0,
+			0,
+		)
+	};
+	Errno::result(ret).map(drop)
+}
+
+/// Whether this process is a child subreaper.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn is_child_subreaper() -> nix::Result<bool> {
+	let mut subreaper: libc::c_int = 0;
+	let ret = unsafe { libc::prctl(libc::PR_GET_CHILD_SUBREAPER, &mut subreaper, 0, 0, 0) };
+	Errno::result(ret).map(|_| subreaper != 0)
+}
+
+/// Make this process a child subreaper (`PR_SET_CHILD_SUBREAPER`), so that orphaned descendants are re-parented to it rather than to init, and reap them as they exit. This lets a process act as a mini init, for example as pid 1 in a container. Linux and Android only.
 ///
-/// - immune to PID race conditions (see [here](https://lwn.net/Articles/773459/) for a description of the race);
-/// - thus it's possible to `waitpid()` on one thread and `kill()` on another without a race;
-/// - option to orphan a process, i.e. hand it off to init;
-/// - child processes are killed on parent termination;
-/// - and it works in the [Capsicum](https://wiki.freebsd.org/Capsicum) capability mode sandbox.
+/// Children forked with [`fork`], [`Spawn`] and [`CommandExt::spawn_handle`] are left to be reaped by their [`ChildHandle`]s. Any other children, including those spawned with `std::process::Command::spawn`, are indistinguishable from orphans and will be reaped too, so their statuses are lost to whatever else is waiting on them.
 ///
-/// It's implemented using process descriptors (pdfork) on FreeBSD and normal fork + an extra process elsewhere.
+/// [`reap`](Subreaper::reap) blocks on a self-pipe written to by a `SIGCHLD` handler, the same one a [`Reaper`] uses, which is installed by the first call to [`new`](Subreaper::new) and left in place for good. Whatever `SIGCHLD` handler it replaces, such as one installed by the program, is neither chained to nor restored.
 ///
 /// # Example
 /// ```no_run
-/// use palaver::process::*;
+/// use palaver::process::Subreaper;
 ///
-/// match fork(false).unwrap() {
-///     ForkResult::Parent(child_proc) => {
-///         // do stuff
-///         // you can access child_proc.pid on any platform
-///         // you can also access child_proc.pd on FreeBSD
-///         if let Err(err) = child_proc.signal(nix::sys::signal::SIGTERM) {
-///             panic!("sigterm: {:?}", err);
-///         }
-///     },
+/// let subreaper = Subreaper::new().unwrap();
+/// for orphan in subreaper.iter() {
+///     let (pid, status) = orphan.unwrap();
+///     println!("orphan {} exited: {:?}", pid, status);
+/// }
+/// ```
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Copy, Clone, Debug)]
+pub struct Subreaper(());
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl Subreaper {
+	/// Make this process a child subreaper. There's no going back: it remains one after the `Subreaper` is dropped, and the first call replaces the `SIGCHLD` handler for good.
+	pub fn new() -> nix::Result<Self> {
+		let mut subreaper = SUBREAPER.lock().unwrap();
+		{
+			let _sigchld = SIGCHLD.write().unwrap();
+			if SUBREAPER_PIPE.load(Ordering::Relaxed) == -1 {
+				let (read, write) = file::pipe(fcntl::OFlag::O_CLOEXEC | fcntl::OFlag::O_NONBLOCK)?;
+				// a Reaper's handler is the same, and once we're here won't be removed when it's dropped
+				if REAPER_PIPE.load(Ordering::Relaxed) == -1 {
+					if let Err(err) =
+						unsafe { signal::sigaction(signal::SIGCHLD, &Reaper::action()) }
+					{
+						let _ = unistd::close(read);
+						let _ = unistd::close(write);
+						return Err(err);
+					}
+				}
+				SUBREAPER_READ.store(read, Ordering::Relaxed);
+				SUBREAPER_PIPE.store(write, Ordering::Relaxed);
+			}
+		}
+		set_child_subreaper(true)?;
+		subreaper.1 = true;
+		Ok(Self(()))
+	}
+	/// Reap an orphan that has exited, if there is one, without blocking. Fails if `/proc` can't be read.
+	pub fn try_reap(&self) -> io::Result<Option<(Pid, WaitStatus)>> {
+		let us = unistd::getpid().as_raw();
+		for entry in fs::read_dir("/proc")? {
+			let pid = entry?.file_name().to_str().and_then(|pid| pid.parse().ok());
+			let stat = pid.and_then(|pid| Some(procfs::process::Process::new(pid).ok()?.stat));
+			if let Some(stat) = stat.filter(|stat| stat.ppid == us && stat.state == 'Z') {
+				let pid = Pid::from_raw(stat.pid);
+				if !children::is_foreign(pid) {
+					continue;
+				}
+				if let Ok(Some(status)) = ChildHandle::try_wait_(pid) {
+					return Ok(Some((pid, status)));
+				}
+			}
+		}
+		Ok(None)
+	}
+	/// Reap the next orphan to exit, blocking till one does. Fails if `/proc` can't be read.
+	pub fn reap(&self) -> io::Result<(Pid, WaitStatus)> {
+		// one thread at a time drains the self-pipe, so that none sleep through a wakeup another has consumed
+		let _reaping = SUBREAPER_REAPING
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		let read = SUBREAPER_READ.load(Ordering::Relaxed);
+		loop {
+			// cleared before checking, so an exit after the check wakes us
+			SUBREAPER_PENDING.store(false, Ordering::Relaxed);
+			while let Ok(1..=64) = unistd::read(read, &mut [0; 64]) {}
+			if let Some(ret) = self.try_reap()? {
+				break Ok(ret);
+			}
+			let mut fds = [poll::PollFd::new(read, poll::PollFlags::POLLIN)];
+			match poll::poll(&mut fds, -1) {
+				Ok(_) | Err(Error::Sys(Errno::EINTR)) => (),
+				Err(err) => break Err(errno(err).into()),
+			}
+		}
+	}
+	/// An iterator that blocks waiting for orphans to exit, reaping them.
+	pub fn iter(&self) -> impl Iterator<Item = io::Result<(Pid, WaitStatus)>> + '_ {
+		iter::repeat_with(move || self.reap())
+	}
+	/// An iterator that reaps orphans that have already exited, without blocking. It ends early, after yielding it, on an error.
+	pub fn try_iter(&self) -> impl Iterator<Item = io::Result<(Pid, WaitStatus)>> + '_ {
+		let mut failed = false;
+		iter::from_fn(move || {
+			if failed {
+				return None;
+			}
+			let ret = self.try_reap().transpose()?;
+			failed = ret.is_err();
+			Some(ret)
+		})
+	}
+}
+
+/// The write end of the [`Subreaper`]'s self-pipe, or -1 if one's yet to be created. Once created it's never closed.
+#[cfg(unix)]
+static SUBREAPER_PIPE: AtomicI32 = AtomicI32::new(-1);
+/// The read end of the [`Subreaper`]'s self-pipe.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+static SUBREAPER_READ: AtomicI32 = AtomicI32::new(-1);
+/// As [`REAPER_PENDING`], for the [`Subreaper`]'s self-pipe.
+#[cfg(unix)]
+static SUBREAPER_PENDING: AtomicBool = AtomicBool::new(false);
+#[cfg(any(target_os = "android", target_os = "linux"))]
+lazy_static::lazy_static! {
+	/// Held by [`Subreaper::reap`] while it drains and waits on the self-pipe.
+	static ref SUBREAPER_REAPING: Mutex<()> = Mutex::new(());
+}
+
+/// Wake any [`Subreaper::reap`], for when a zombie that was our own child might have become an orphan in its eyes, as when a fork ends.
+#[cfg(unix)]
+fn wake_subreaper() {
+	let write = SUBREAPER_PIPE.load(Ordering::Relaxed);
+	if write != -1 && !SUBREAPER_PENDING.swap(true, Ordering::Relaxed) {
+		let _ = unistd::write(write, &[0]);
+	}
+}
+
+#[cfg(unix)]
+lazy_static::lazy_static! {
+	/// Held for reading by [`fork`] while it changes the `SIGCHLD` disposition to orphan a child, and for writing while a [`Reaper`] is created or dropped.
+	static ref SIGCHLD: RwLock<()> = RwLock::new(());
+}
+/// The write end of the [`Reaper`]'s self-pipe, or -1 if there isn't one.
+#[cfg(unix)]
+static REAPER_PIPE: AtomicI32 = AtomicI32::new(-1);
+/// Set by the `SIGCHLD` handler when it writes to the self-pipe, and cleared by the reaper thread once it's drained it, so the pipe never holds more than a byte and the write can't fail and clobber `errno`.
+#[cfg(unix)]
+static REAPER_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Reap [`fork`]ed children as they exit on a single background thread, rather than with a thread blocked in [`ChildHandle::wait`] for each, and deliver their pids and statuses.
+///
+/// Children are handed over with [`add`](Reaper::add). The thread waits for `SIGCHLD`, which a handler installed by [`Reaper::new`] turns into a write to a self-pipe, and for the pidfds of children that have them on Linux and process descriptors on FreeBSD, as those children don't raise `SIGCHLD`. It reaps them with [`ChildHandle::try_wait`], so other threads waiting on them get the same status.
+///
+/// Only one `Reaper` can exist at a time. While it does, [`fork(true)`](fork) leaves its handler in place rather than resetting `SIGCHLD` to the default. On drop the previous disposition is restored, and children yet to exit are dropped, which kills them unless they're still referenced elsewhere.
+///
+/// # Example
+/// ```no_run
+/// use palaver::process::*;
+/// use std::{process, sync::Arc};
+///
+/// let reaper = Reaper::new().unwrap();
+/// for _ in 0..100 {
+///     match fork(false).unwrap() {
+///         ForkResult::Parent(child) => reaper.add(Arc::new(child)),
+///         ForkResult::Child => process::exit(0),
+///     }
+/// }
+/// for (pid, status) in reaper.iter().take(100) {
+///     println!("child {} exited: {:?}", pid, status);
+/// }
+/// ```
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Reaper {
+	shared: Arc<ReaperShared>,
+	exited: Mutex<mpsc::Receiver<(Pid, WaitStatus)>>,
+	pipe: (Fd, Fd),
+	old: signal::SigAction,
+	thread: Option<thread::JoinHandle<()>>,
+}
+#[cfg(unix)]
+#[derive(Debug)]
+struct ReaperShared {
+	children: Mutex<Vec<Arc<ChildHandle>>>,
+	stop: AtomicBool,
+}
+#[cfg(unix)]
+impl Reaper {
+	/// Install the `SIGCHLD` handler and spawn the reaper thread. Fails with `EBUSY` if a `Reaper` already exists.
+	///
+	/// # Panics
+	///
+	/// Panics if the thread can't be spawned.
+	pub fn new() -> nix::Result<Self> {
+		let _sigchld = SIGCHLD.write().unwrap();
+		if REAPER_PIPE.load(Ordering::Relaxed) != -1 {
+			return Err(Error::Sys(Errno::EBUSY));
+		}
+		let (read, write) = file::pipe(fcntl::OFlag::O_CLOEXEC | fcntl::OFlag::O_NONBLOCK)?;
+		REAPER_PIPE.store(write, Ordering::Relaxed);
+		REAPER_PENDING.store(false, Ordering::Relaxed);
+		let old = match unsafe { signal::sigaction(signal::SIGCHLD, &Self::action()) } {
+			Ok(old) => old,
+			Err(err) => {
+				REAPER_PIPE.store(-1, Ordering::Relaxed);
+				let _ = unistd::close(read);
+				let _ = unistd::close(write);
+				return Err(err);
+			}
+		};
+		let shared = Arc::new(ReaperShared {
+			children: Mutex::new(Vec::new()),
+			stop: AtomicBool::new(false),
+		});
+		let (sender, receiver) = mpsc::channel();
+		let shared_ = shared.clone();
+		let thread = thread::Builder::new()
+			.name(String::from("palaver-reaper"))
+			.spawn(move || Self::run(&shared_, read, &sender))
+			.unwrap();
+		Ok(Self {
+			shared,
+			exited: Mutex::new(receiver),
+			pipe: (read, write),
+			old,
+			thread: Some(thread),
+		})
+	}
+	/// The `SIGCHLD` action, which wakes the reaper thread and any [`Subreaper::reap`]. `SA_NOCLDSTOP` as stops and continues aren't reaped.
+	fn action() -> signal::SigAction {
+		extern "C" fn handler(_: libc::c_int) {
+			let write = REAPER_PIPE.load(Ordering::Relaxed);
+			if write != -1 && !REAPER_PENDING.swap(true, Ordering::Relaxed) {
+				let _ = unsafe { libc::write(write, [0_u8].as_ptr().cast(), 1) };
+			}
+			let write = SUBREAPER_PIPE.load(Ordering::Relaxed);
+			if write != -1 && !SUBREAPER_PENDING.swap(true, Ordering::Relaxed) {
+				let _ = unsafe { libc::write(write, [0_u8].as_ptr().cast(), 1) };
+			}
+		}
+		signal::SigAction::new(
+			signal::SigHandler::Handler(handler),
+			signal::SaFlags::SA_RESTART | signal::SaFlags::SA_NOCLDSTOP,
+			signal::SigSet::empty(),
+		)
+	}
+	/// The reaper thread: block till the self-pipe or an exit fd is readable, then reap and deliver the children that have exited.
+	fn run(shared: &ReaperShared, read: Fd, sender: &mpsc::Sender<(Pid, WaitStatus)>) {
+		loop {
+			let mut fds = {
+				let children = shared.children.lock().unwrap();
+				iter::once(read)
+					.chain(children.iter().filter_map(|child| child.exit_fd_()))
+					.map(|fd| poll::PollFd::new(fd, poll::PollFlags::POLLIN))
+					.collect::<Vec<_>>()
+			};
+			match poll::poll(&mut fds, -1) {
+				Ok(_) | Err(Error::Sys(Errno::EINTR)) => (),
+				Err(Error::Sys(Errno::ENOMEM)) => thread::sleep(Duration::from_millis(10)),
+				// likely more exit fds than `RLIMIT_NOFILE`, so check on the children periodically instead
+				Err(_) => {
+					let _ = poll::poll(&mut fds[..1], 100);
+				}
+			}
+			while let Ok(1) = unistd::read(read, &mut [0]) {}
+			REAPER_PENDING.store(false, Ordering::Relaxed);
+			if shared.stop.load(Ordering::Relaxed) {
+				break;
+			}
+			// waited on outside the lock, so `add` isn't held up, then taken out of the list under it, but dropped, cleaning them up, outside it
+			let children = shared.children.lock().unwrap().clone();
+			let exited = children
+				.into_iter()
+				.filter_map(|child| match child.try_wait() {
+					Ok(None) => None,
+					ret => Some((child, ret.ok().flatten())),
+				})
+				.collect::<Vec<_>>();
+			if !exited.is_empty() {
+				shared
+					.children
+					.lock()
+					.unwrap()
+					.retain(|child| !exited.iter().any(|(exited, _)| Arc::ptr_eq(child, exited)));
+			}
+			for (child, status) in exited {
+				if let Some(status) = status {
+					let _ = sender.send((child.pid, status));
+				}
+			}
+		}
+	}
+	/// Hand over a child to be reaped once it exits. It can still be signalled or waited on through other references to it.
+	///
+	/// # Panics
+	///
+	/// Panics if the reaper thread has panicked.
+	pub fn add(&self, child: Arc<ChildHandle>) {
+		self.shared.children.lock().unwrap().push(child);
+		// wake the thread to poll the child's exit fd, or reap it if it's already exited
+		if !REAPER_PENDING.swap(true, Ordering::Relaxed) {
+			let _ = unistd::write(self.pipe.1, &[0]);
+		}
+	}
+	/// Take the next child that's been reaped, if there is one, without blocking.
+	///
+	/// # Panics
+	///
+	/// Panics if the reaper thread has panicked.
+	pub fn try_reap(&self) -> Option<(Pid, WaitStatus)> {
+		self.exited.lock().unwrap().try_recv().ok()
+	}
+	/// Take the next child to be reaped, blocking till one is.
+	///
+	/// # Panics
+	///
+	/// Panics if the reaper thread has panicked.
+	pub fn reap(&self) -> (Pid, WaitStatus) {
+		self.exited.lock().unwrap().recv().unwrap()
+	}
+	/// An iterator that blocks waiting for children to be reaped.
+	pub fn iter(&self) -> impl Iterator<Item = (Pid, WaitStatus)> + '_ {
+		iter::repeat_with(move || self.reap())
+	}
+	/// An iterator over children that have already been reaped, without blocking.
+	pub fn try_iter(&self) -> impl Iterator<Item = (Pid, WaitStatus)> + '_ {
+		iter::from_fn(move || self.try_reap())
+	}
+}
+#[cfg(unix)]
+impl Drop for Reaper {
+	fn drop(&mut self) {
+		self.shared.stop.store(true, Ordering::Relaxed);
+		let _ = unistd::write(self.pipe.1, &[0]);
+		self.thread.take().unwrap().join().unwrap();
+		let _sigchld = SIGCHLD.write().unwrap();
+		// cleared first, so the handler doesn't write to a closed fd
+		REAPER_PIPE.store(-1, Ordering::Relaxed);
+		// a Subreaper still needs the handler
+		if SUBREAPER_PIPE.load(Ordering::Relaxed) == -1 {
+			let _ = unsafe { signal::sigaction(signal::SIGCHLD, &self.old) };
+		}
+		unistd::close(self.pipe.0).unwrap();
+		unistd::close(self.pipe.1).unwrap();
+	}
+}
+
+#[cfg(unix)]
+impl AsRawFd for ChildHandle {
+	/// The pidfd on Linux 5.3+ or the process descriptor on FreeBSD, and otherwise [`exit_fd`](ChildHandle::exit_fd).
+	///
+	/// # Panics
+	///
+	/// Panics where `exit_fd` would fail: if the child is orphaned, other than on FreeBSD, or if the pipe or helper thread can't be created.
+	fn as_raw_fd(&self) -> Fd {
+		self.exit_fd()
+			.expect(".as_raw_fd() couldn't create the child's exit fd")
+	}
+}
+
+/// Emulate a pidfd with a pipe, the write end of which is closed by a helper thread once `waitid(WNOWAIT)` sees the child exit. `WNOWAIT` leaves the child to be reaped by [`ChildHandle::wait`]. A `received` child that isn't ours is instead polled for with `kill(pid, 0)` till it's been reaped, or till the read end's closed by the handle being dropped, which the write end polls as an error.
+#[cfg(not(target_os = "freebsd"))]
+fn exit_pipe_(pid: Pid, received: bool) -> io::Result<(Fd, thread::JoinHandle<()>)> {
+	let (read, write) = file::pipe(fcntl::OFlag::O_CLOEXEC)
+		.map_err(|err| io::Error::from_raw_os_error(errno(err) as i32))?;
+	let thread = match thread::Builder::new()
+		.name(String::from("palaver-exit-pipe"))
+		.stack_size(16 * 1024)
+		.spawn(move || {
+			loop {
+				let mut info = mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+				let ret = unsafe {
+					libc::waitid(
+						libc::P_PID,
+						pid.as_raw().try_into().unwrap(),
+						info.as_mut_ptr(),
+						libc::WEXITED | libc::WNOWAIT,
+					)
+				};
+				match Errno::result(ret) {
+					Err(Error::Sys(Errno::EINTR)) => (),
+					Err(Error::Sys(Errno::ECHILD)) if received => {
+						let mut fds = [poll::PollFd::new(write, poll::PollFlags::empty())];
+						while signal::kill(pid, None).is_ok() {
+							match poll::poll(&mut fds, 10) {
+								Ok(0) => (),
+								Ok(_) => break,
+								Err(_) => thread::sleep(Duration::from_millis(10)),
+							}
+						}
+						break;
+					}
+					// Err(ECHILD) if it's been reaped from under us, which is just as good
+					_ => break,
+				}
+			}
+			let _ = unistd::close(write);
+		}) {
+		Ok(thread) => thread,
+		Err(err) => {
+			let _ = unistd::close(read);
+			let _ = unistd::close(write);
+			return Err(err);
+		}
+	};
+	Ok((read, thread))
+}
+
+#[cfg(unix)]
+impl Drop for ChildHandle {
+	fn drop(&mut self) {
+		if self.owns.is_some() {
+			let state = *self.owns.as_mut().unwrap().state.get_mut();
+			if state == 0 {
+				let _ = self.signal(signal::SIGKILL);
+			}
+			// a received child is likely someone else's to reap, so is left to them
+			if state != 2 && !self.received {
+				let _ = self.wait().unwrap();
+			}
+			#[cfg(not(target_os = "freebsd"))]
+			{
+				// the helper thread returns promptly now the child's been reaped or killed, or if it's polling once the read end's closed
+				let exit_pipe = self
+					.owns
+					.as_mut()
+					.map(|owns| owns.exit_pipe.get_mut().unwrap());
+				if let Some((read, thread)) = exit_pipe.and_then(Option::take) {
+					unistd::close(read).unwrap();
+					thread.join().unwrap();
+				}
+			}
+			#[cfg(target_os = "linux")]
+			let pidfd = self.owns.as_ref().and_then(|owns| owns.pidfd);
+			#[cfg(not(target_os = "linux"))]
+			let pidfd: Option<Fd> = None;
+			if let Some(pidfd) = pidfd {
+				unistd::close(pidfd).unwrap();
+			} else if !matches!(&self.owns, Some(owns) if owns.retainer_killed.load(Ordering::Relaxed))
+			{
+				// kill the pid retainer, which is in the group `pid`
+				let group = Pid::from_raw(-self.pid.as_raw());
+				let _ = signal::kill(group, signal::SIGKILL);
+			}
+			#[cfg(not(target_os = "freebsd"))]
+			{
+				if let Some(guard_write) = self.owns.as_ref().and_then(|owns| owns.guard_write) {
+					unistd::close(guard_write).unwrap();
+				}
+			}
+		}
+		#[cfg(target_os = "freebsd")]
+		{
+			if self.pd != i32::max_value() {
+				unistd::close(self.pd).unwrap();
+			}
+		}
+		children::remove(&self.tracked);
+	}
+}
+
+/// Fork result
+#[cfg(unix)]
+#[derive(Debug)]
+pub enum ForkResult {
+	/// Parent process
+	Parent(ChildHandle),
+	/// Child process
+	Child,
+}
+
+/// An error from [`fork`], [`fork_with`], [`daemonize`], [`ForkServer`] or [`Spawn`], saying which stage failed.
+#[cfg(unix)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ForkError {
+	/// Creating a pipe or socket to communicate with the child failed.
+	Pipe(Errno),
+	/// Changing signal dispositions or the signal mask failed.
+	Signal(Errno),
+	/// Forking the child, or one of the helper processes, failed.
+	Fork(Errno),
+	/// Moving the child into or out of its own process group failed.
+	ProcessGroup(Errno),
+	/// Opening a pidfd for the child failed.
+	Pidfd(Errno),
+	/// Passing a pid or fd between the parent, child and helper processes failed.
+	Handoff(Errno),
+	/// Creating the child's namespaces, or writing its uid or gid map, failed.
+	Namespace(Errno),
+	/// Setting the child's resource limits failed.
+	Rlimit(Errno),
+	/// Changing directory, or redirecting stdio, in the daemon failed.
+	Daemon(Errno),
+	/// Starting a [`ForkServer`], or passing a request or reply to or from it, failed.
+	Server(Errno),
+	/// Mapping fds for, changing directory in, or executing the program of a [`Spawn`] failed.
+	Exec(Errno),
+}
+#[cfg(unix)]
+impl ForkError {
+	/// The error number of the failed call.
+	pub fn errno(self) -> Errno {
+		match self {
+			Self::Pipe(errno)
+			| Self::Signal(errno)
+			| Self::Fork(errno)
+			| Self::ProcessGroup(errno)
+			| Self::Pidfd(errno)
+			| Self::Handoff(errno)
+			| Self::Namespace(errno)
+			| Self::Rlimit(errno)
+			| Self::Daemon(errno)
+			| Self::Server(errno)
+			| Self::Exec(errno) => errno,
+		}
+	}
+	/// Encoding for sending from the child, which reports its failures to the parent before exiting.
+	fn to_bytes(self) -> [u8; 5] {
+		let stage = match self {
+			Self::Pipe(_) => 0,
+			Self::Signal(_) => 1,
+			Self::Fork(_) => 2,
+			Self::ProcessGroup(_) => 3,
+			Self::Pidfd(_) => 4,
+			Self::Handoff(_) => 5,
+			Self::Namespace(_) => 6,
+			Self::Rlimit(_) => 7,
+			Self::Daemon(_) => 8,
+			Self::Server(_) => 9,
+			Self::Exec(_) => 10,
+		};
+		let errno = (self.errno() as i32).to_ne_bytes();
+		[stage, errno[0], errno[1], errno[2], errno[3]]
+	}
+	fn from_bytes(bytes: [u8; 5]) -> Self {
+		let errno = Errno::from_i32(i32::from_ne_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]));
+		match bytes[0] {
+			0 => Self::Pipe(errno),
+			1 => Self::Signal(errno),
+			2 => Self::Fork(errno),
+			3 => Self::ProcessGroup(errno),
+			4 => Self::Pidfd(errno),
+			6 => Self::Namespace(errno),
+			7 => Self::Rlimit(errno),
+			8 => Self::Daemon(errno),
+			9 => Self::Server(errno),
+			10 => Self::Exec(errno),
+			_ => Self::Handoff(errno),
+		}
+	}
+}
+#[cfg(unix)]
+impl fmt::Display for ForkError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let stage = match self {
+			Self::Pipe(_) => "creating a pipe",
+			Self::Signal(_) => "changing signal handling",
+			Self::Fork(_) => "forking",
+			Self::ProcessGroup(_) => "changing process group",
+			Self::Pidfd(_) => "opening a pidfd",
+			Self::Handoff(_) => "passing a pid or fd to the parent",
+			Self::Namespace(_) => "creating namespaces",
+			Self::Rlimit(_) => "setting resource limits",
+			Self::Daemon(_) => "setting up the daemon",
+			Self::Server(_) => "communicating with the fork server",
+			Self::Exec(_) => "executing the program",
+		};
+		write!(f, "fork failed {}: {}", stage, self.errno().desc())
+	}
+}
+#[cfg(unix)]
+impl std::error::Error for ForkError {}
+#[cfg(unix)]
+impl From<ForkError> for Error {
+	fn from(err: ForkError) -> Self {
+		Self::Sys(err.errno())
+	}
+}
+
+#[cfg(unix)]
+fn errno(err: Error) -> Errno {
+	err.as_errno().unwrap_or(Errno::UnknownErrno)
+}
+#[cfg(unix)]
+fn io_errno(err: &io::Error) -> Errno {
+	Errno::from_i32(err.raw_os_error().unwrap_or(0))
+}
+
+/// A Rust fork wrapper that provides more coherent, FreeBSD-inspired semantics.
+///
+/// - immune to PID race conditions (see [here](https://lwn.net/Articles/773459/) for a description of the race);
+/// - thus it's possible to `waitpid()` on one thread and `kill()` on another without a race;
+/// - option to orphan a process, i.e. hand it off to init;
+/// - child processes are killed on parent termination;
+/// - and it works in the [Capsicum](https://wiki.freebsd.org/Capsicum) capability mode sandbox.
+///
+/// It's implemented using process descriptors (pdfork) on FreeBSD, normal fork + a [pidfd](https://lwn.net/Articles/794707/) + [`PR_SET_PDEATHSIG`](http://man7.org/linux/man-pages/man2/prctl.2.html) on Linux 5.3+ when called from the main thread (or a watchdog process when called from other threads, as the signal is tied to the forking thread), and normal fork + extra processes elsewhere.
+///
+/// Note that `PR_SET_PDEATHSIG` is cleared if the child execs a set-user-ID or set-group-ID binary, after which it will no longer be killed on the parent's death.
+///
+/// Failures, including those setting up the helper processes in the child, are returned to the parent as a [`ForkError`], after any helper processes and fds have been cleaned up. A child that fails to set up exits rather than returning.
+///
+/// # Panics
+///
+/// Panics if another thread changes the `SIGCHLD` disposition while this runs, other than by creating or dropping a [`Reaper`], which waits for it.
+///
+/// # Example
+/// ```no_run
+/// use palaver::process::*;
+///
+/// match fork(false).unwrap() {
+///     ForkResult::Parent(child_proc) => {
+///         // do stuff
+///         // you can access child_proc.pid on any platform
+///         // you can also access child_proc.pd on FreeBSD
+///         if let Err(err) = child_proc.signal(nix::sys::signal::SIGTERM) {
+///             panic!("sigterm: {:?}", err);
+///         }
+///     },
 ///     ForkResult::Child => {
 ///         // do stuff
 ///     }
@@ -214,169 +1564,2086 @@ pub enum ForkResult {
 /// ```
 // See also https://github.com/qt/qtbase/blob/v5.12.0/src/3rdparty/forkfd/forkfd.c
 #[cfg(unix)]
+pub fn fork(orphan: bool) -> Result<ForkResult, ForkError> {
+	with_handlers(|| fork_(orphan, true, false))
+}
+/// Run the [`atfork`] handlers around `fork`.
+#[cfg(unix)]
+fn with_handlers(
+	fork: impl FnOnce() -> Result<ForkResult, ForkError>,
+) -> Result<ForkResult, ForkError> {
+	handlers::prepare();
+	let ret = fork();
+	if let Ok(ForkResult::Child) = ret {
+		handlers::child();
+	} else {
+		handlers::parent();
+	}
+	ret
+}
+/// If not `pdeathsig`, a non-orphan is always killed by way of its handle's guard fd, so once it's been sent elsewhere doesn't die with us. If `setsid`, an orphan's intermediate parent starts a new session before forking it, so it's in the session without being its leader.
+#[cfg(unix)]
 #[allow(clippy::too_many_lines)]
-pub fn fork(orphan: bool) -> nix::Result<ForkResult> {
+fn fork_(orphan: bool, pdeathsig: bool, setsid: bool) -> Result<ForkResult, ForkError> {
 	if orphan {
 		// inspired by fork2 http://www.faqs.org/faqs/unix-faq/programmer/faq/
 		// TODO: make this not racy, could add a third fork?
-		let new = signal::SigAction::new(
-			signal::SigHandler::SigDfl,
-			signal::SaFlags::empty(),
-			signal::SigSet::empty(),
-		);
-		let old = unsafe { signal::sigaction(signal::SIGCHLD, &new).unwrap() };
+		// a Reaper's or Subreaper's handler doesn't stop us waiting on the intermediate child so is left in place, and it can't come or go till we're done
+		let _sigchld = SIGCHLD.read().unwrap();
+		let new = if REAPER_PIPE.load(Ordering::Relaxed) == -1
+			&& SUBREAPER_PIPE.load(Ordering::Relaxed) == -1
+		{
+			signal::SigAction::new(
+				signal::SigHandler::SigDfl,
+				signal::SaFlags::empty(),
+				signal::SigSet::empty(),
+			)
+		} else {
+			Reaper::action()
+		};
+		let old = unsafe { signal::sigaction(signal::SIGCHLD, &new) }
+			.map_err(|err| ForkError::Signal(errno(err)))?;
 		let ret = (|| {
-			let child = if let ForkResult::Parent(child) = basic_fork(false)? {
-				child
-			} else {
-				match basic_fork(true) {
-					Ok(ForkResult::Child) => {
-						return Ok(ForkResult::Child);
+			// the intermediate child reports the grandchild's pid, or a ForkError, and on FreeBSD the grandchild's process descriptor, back to us
+			let (pid_read, pid_write) =
+				file::pipe(fcntl::OFlag::O_CLOEXEC).map_err(|err| ForkError::Pipe(errno(err)))?;
+			#[cfg(target_os = "freebsd")]
+			let (pd_read, pd_write) = UnixDatagram::pair().map_err(|err| {
+				let _ = unistd::close(pid_read);
+				let _ = unistd::close(pid_write);
+				ForkError::Pipe(io_errno(&err))
+			})?;
+			let child = match basic_fork(false) {
+				Ok(ForkResult::Parent(child)) => child,
+				Ok(ForkResult::Child) => {
+					let _ = unistd::close(pid_read);
+					#[cfg(target_os = "freebsd")]
+					drop(pd_read);
+					if setsid {
+						if let Err(err) = unistd::setsid() {
+							let err = ForkError::ProcessGroup(errno(err)).to_bytes();
+							let _ = unistd::write(pid_write, &err);
+							unsafe { libc::_exit(1) }
+						}
+					}
+					match basic_fork(true) {
+						Ok(ForkResult::Child) => {
+							let _ = unistd::close(pid_write);
+							#[cfg(target_os = "freebsd")]
+							drop(pd_write);
+							return Ok(ForkResult::Child);
+						}
+						Ok(ForkResult::Parent(grandchild)) => {
+							#[cfg(target_os = "freebsd")]
+							{
+								// sent before the pid, so it's there to be received once the pid's read
+								if let Err(err) = send_fd::send_fd(grandchild.pd, &pd_write) {
+									let _ = signal::kill(grandchild.pid, signal::SIGKILL);
+									let err = ForkError::Handoff(errno(err)).to_bytes();
+									let _ = unistd::write(pid_write, &err);
+									unsafe { libc::_exit(1) }
+								}
+							}
+							let pid = grandchild.pid.as_raw().to_ne_bytes();
+							if unistd::write(pid_write, &pid) != Ok(pid.len()) {
+								let _ = signal::kill(grandchild.pid, signal::SIGKILL);
+								unsafe { libc::_exit(1) }
+							}
+							unsafe { libc::_exit(0) }
+						}
+						Err(err) => {
+							let err = ForkError::Fork(errno(err)).to_bytes();
+							let _ = unistd::write(pid_write, &err);
+							unsafe { libc::_exit(1) }
+						}
+					}
+				}
+				Err(err) => {
+					let _ = unistd::close(pid_read);
+					let _ = unistd::close(pid_write);
+					return Err(ForkError::Fork(errno(err)));
+				}
+			};
+			let _ = unistd::close(pid_write);
+			#[cfg(target_os = "freebsd")]
+			drop(pd_write);
+			let mut msg = [0; 5];
+			let read = unistd::read(pid_read, &mut msg);
+			let _ = unistd::close(pid_read);
+			let exit = child.wait();
+			match (read, exit) {
+				(Ok(4), Ok(WaitStatus::Exited(0))) => {
+					let pid = Pid::from_raw(i32::from_ne_bytes([msg[0], msg[1], msg[2], msg[3]]));
+					#[cfg(target_os = "freebsd")]
+					let pd = send_fd::receive_fd(&pd_read).map_err(|err| {
+						let _ = signal::kill(pid, signal::SIGKILL);
+						ForkError::Handoff(errno(err))
+					})?;
+					Ok(ForkResult::Parent(ChildHandle {
+						pid,
+						#[cfg(target_os = "freebsd")]
+						pd,
+						owns: None,
+						tracked: children::Tracked::default(),
+						status: Mutex::new(None),
+						received: false,
+					}))
+				}
+				(Ok(5), _) => Err(ForkError::from_bytes(msg)),
+				(Err(err), _) => Err(ForkError::Handoff(errno(err))),
+				_ => Err(ForkError::Handoff(Errno::EPIPE)),
+			}
+		})();
+		if new.handler() != old.handler() {
+			match unsafe { signal::sigaction(signal::SIGCHLD, &old) } {
+				Ok(new2) => assert_eq!(new.handler(), new2.handler()),
+				Err(err) => {
+					match ret {
+						Ok(ForkResult::Parent(ref child)) => {
+							let _ = signal::kill(child.pid, signal::SIGKILL);
+						}
+						Ok(ForkResult::Child) => unsafe { libc::_exit(1) },
+						Err(_) => (),
+					}
+					return Err(ForkError::Signal(errno(err)));
+				}
+			}
+		}
+		ret
+	} else {
+		if cfg!(target_os = "freebsd") {
+			return basic_fork(false).map_err(|err| ForkError::Fork(errno(err)));
+		}
+		#[cfg(target_os = "linux")]
+		let use_pidfd = pidfd::is_supported();
+		// PR_SET_PDEATHSIG fires on the exit of the forking thread rather than process, so only stands in for the watchdog when that's the main thread
+		#[cfg(target_os = "linux")]
+		{
+			if use_pidfd && pdeathsig && unistd::gettid() == unistd::getpid() {
+				return fork_pdeathsig();
+			}
+		}
+		let (ready_read, ready_write) =
+			UnixDatagram::pair().map_err(|err| ForkError::Pipe(io_errno(&err)))?;
+		Ok(
+			match basic_fork(false).map_err(|err| ForkError::Fork(errno(err)))? {
+				ForkResult::Child => {
+					drop(ready_read);
+					#[cfg(target_os = "linux")]
+					let ret = if use_pidfd {
+						fork_watchdog(&ready_write, None)
+					} else {
+						fork_retainers(&ready_write)
+					};
+					#[cfg(not(target_os = "linux"))]
+					let ret = fork_retainers(&ready_write);
+					if let Err(err) = ret {
+						let _ = ready_write.send(&err.to_bytes());
+						unsafe { libc::_exit(1) }
+					}
+					ForkResult::Child
+				}
+				ForkResult::Parent(mut child) => {
+					drop(ready_write);
+					let guard_write = receive_guard(&ready_read);
+					drop(ready_read);
+					#[cfg(target_os = "linux")]
+					let guard_write = guard_write.and_then(|guard_write| {
+						if !use_pidfd {
+							return Ok((guard_write, None));
+						}
+						// the child is yet to be reaped, so its pid can't have been reused
+						pidfd::open(child.pid)
+							.map(|pidfd| (guard_write, Some(pidfd)))
+							.map_err(|err| {
+								let _ = unistd::close(guard_write);
+								ForkError::Pidfd(errno(err))
+							})
+					});
+					let guard_write = match guard_write {
+						Ok(guard_write) => guard_write,
+						Err(err) => {
+							let _ = signal::kill(child.pid, signal::SIGKILL);
+							let _ = ChildHandle::wait_(child.pid);
+							child.set_reaped();
+							return Err(err);
+						}
+					};
+					#[cfg(target_os = "linux")]
+					let (guard_write, pidfd) = guard_write;
+					child.owns = Some(Handle {
+						state: AtomicU8::new(0),
+						retainer_killed: AtomicBool::new(false),
+						#[cfg(not(target_os = "freebsd"))]
+						guard_write: Some(guard_write),
+						#[cfg(target_os = "linux")]
+						pidfd,
+						#[cfg(not(target_os = "freebsd"))]
+						exit_pipe: Mutex::new(None),
+					});
+					let _ = guard_write;
+					ForkResult::Parent(child)
+				}
+			},
+		)
+	}
+}
+
+#[cfg(target_os = "linux")]
+bitflags::bitflags! {
+	/// Namespaces for [`fork_with`] to create the child in. See [namespaces(7)](http://man7.org/linux/man-pages/man7/namespaces.7.html).
+	#[derive(Default)]
+	pub struct Namespaces: libc::c_int {
+		/// User namespace, with the ids in [`ForkOptions::uid_map`] and [`ForkOptions::gid_map`] mapped into it
+		const USER = libc::CLONE_NEWUSER;
+		/// PID namespace, in which the child is init, i.e. pid 1
+		const PID = libc::CLONE_NEWPID;
+		/// Mount namespace
+		const MOUNT = libc::CLONE_NEWNS;
+		/// Network namespace
+		const NET = libc::CLONE_NEWNET;
+		/// IPC namespace
+		const IPC = libc::CLONE_NEWIPC;
+		/// UTS namespace, i.e. hostname and NIS domain name
+		const UTS = libc::CLONE_NEWUTS;
+	}
+}
+
+/// A line of a user namespace's uid or gid map: `count` ids starting from `outside` in the parent's namespace appear as those starting from `inside` in the child's. See [user_namespaces(7)](http://man7.org/linux/man-pages/man7/user_namespaces.7.html).
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IdMap {
+	/// First id inside the namespace
+	pub inside: u32,
+	/// First id outside the namespace
+	pub outside: u32,
+	/// Number of ids
+	pub count: u32,
+}
+
+/// Options for [`fork_with`]
+#[cfg(unix)]
+#[derive(Clone, Default, Debug)]
+pub struct ForkOptions {
+	/// Namespaces to create the child in. Linux only.
+	#[cfg(target_os = "linux")]
+	pub namespaces: Namespaces,
+	/// Written to the child's `uid_map`. Requires [`Namespaces::USER`].
+	#[cfg(target_os = "linux")]
+	pub uid_map: Vec<IdMap>,
+	/// Written to the child's `gid_map`, after writing `deny` to its `setgroups` unless we're root, as an unprivileged process can't map gids otherwise. Requires [`Namespaces::USER`].
+	#[cfg(target_os = "linux")]
+	pub gid_map: Vec<IdMap>,
+	/// Resource limits for the child to set, in order, before `fork_with` returns in it.
+	pub rlimits: Vec<(rlimit::Resource, rlimit::Rlimit)>,
+	/// Have the child killed once every copy of its handle, including those passed to other processes with [`ChildHandle::send`], has been dropped or its holder has exited, rather than when we exit. This only makes a difference on the main thread on Linux, where by default it's tied to us by `PR_SET_PDEATHSIG`; instead a watchdog process watches the handle, as when forking from other threads. A child in new [`namespaces`](ForkOptions::namespaces) is always watched this way, so is transferable regardless.
+	pub transferable: bool,
+}
+
+/// [`fork`] with options: for setting the child's resource limits, for letting it outlive us once its handle's been sent to another process, and on Linux 5.3+ for creating it in new namespaces.
+///
+/// The limits are set by the child once it's otherwise set up, so they don't apply to the helper processes, and a failure is returned to the parent as [`ForkError::Rlimit`].
+///
+/// With namespaces, the child is killed on parent termination and immune to PID race conditions as with `fork(false)`, but always by way of a pidfd and a watchdog process. The child `unshare`s the namespaces, after which for a user namespace we write its id maps. A process can't move into a new pid namespace itself, so the child then forks again into one, with `CLONE_PARENT` so that the new process is still ours to wait on. [`ChildHandle::pid`] is its pid in our namespace, while in its own it's 1. As init of its namespace it's immune to signals it has no handler for from processes inside the namespace, and its exit kills the rest of them. That fork is a raw `clone3`, which leaves libc's cached TID stale, so the child should avoid `raise`, `abort` and `pthread_kill`, for example by `exec`ing.
+///
+/// With no options set this is `fork(false)`.
+///
+/// # Examples
+/// ```no_run
+/// use palaver::{process::*, rlimit};
+///
+/// let options = ForkOptions {
+///     rlimits: vec![(
+///         rlimit::Resource::RLIMIT_NOFILE,
+///         rlimit::Rlimit { soft: Some(64), hard: Some(64) },
+///     )],
+///     ..ForkOptions::default()
+/// };
+/// match fork_with(&options).unwrap() {
+///     ForkResult::Parent(child_proc) => {
+///         let _ = child_proc.wait().unwrap();
+///     }
+///     ForkResult::Child => {
+///         assert_eq!(rlimit::get(rlimit::Resource::RLIMIT_NOFILE).unwrap().soft, Some(64));
+///     }
+/// }
+/// ```
+///
+/// In new user and pid namespaces, on Linux:
+/// ```no_run
+/// # #[cfg(target_os = "linux")]
+/// # {
+/// use nix::unistd;
+/// use palaver::process::*;
+///
+/// let options = ForkOptions {
+///     namespaces: Namespaces::USER | Namespaces::PID,
+///     uid_map: vec![IdMap { inside: 0, outside: unistd::getuid().as_raw(), count: 1 }],
+///     ..ForkOptions::default()
+/// };
+/// match fork_with(&options).unwrap() {
+///     ForkResult::Parent(child_proc) => {
+///         // child_proc.pid is the child's pid in our namespace
+///         let _ = child_proc.wait().unwrap();
+///     }
+///     ForkResult::Child => {
+///         assert_eq!(unistd::getpid().as_raw(), 1);
+///         assert!(unistd::getuid().is_root());
+///     }
+/// }
+/// # }
+/// ```
+#[cfg(unix)]
+pub fn fork_with(options: &ForkOptions) -> Result<ForkResult, ForkError> {
+	#[cfg(target_os = "linux")]
+	let fork_ = || {
+		if !options.namespaces.is_empty() {
+			// always with a watchdog, so `transferable` holds either way
+			fork_namespaces(options)
+		} else if options.transferable {
+			with_handlers(|| fork_(false, false, false))
+		} else {
+			fork(false)
+		}
+	};
+	#[cfg(not(target_os = "linux"))]
+	let fork_ = || fork(false);
+	if options.rlimits.is_empty() {
+		return fork_();
+	}
+	// the child reports a failure to set the limits, and otherwise just closes its end
+	let (read, write) =
+		file::pipe(fcntl::OFlag::O_CLOEXEC).map_err(|err| ForkError::Pipe(errno(err)))?;
+	match fork_() {
+		Ok(ForkResult::Child) => {
+			let _ = unistd::close(read);
+			for &(resource, limit) in &options.rlimits {
+				if let Err(err) = rlimit::set(resource, limit) {
+					let _ = unistd::write(write, &ForkError::Rlimit(errno(err)).to_bytes());
+					unsafe { libc::_exit(1) }
+				}
+			}
+			let _ = unistd::close(write);
+			Ok(ForkResult::Child)
+		}
+		Ok(ForkResult::Parent(child)) => {
+			let _ = unistd::close(write);
+			let mut msg = [0; 5];
+			let ret = unistd::read(read, &mut msg);
+			let _ = unistd::close(read);
+			// on failure the child is killed and reaped as it's dropped
+			match ret {
+				Ok(0) => Ok(ForkResult::Parent(child)),
+				Ok(5) => Err(ForkError::from_bytes(msg)),
+				Ok(_) => Err(ForkError::Handoff(Errno::EBADMSG)),
+				Err(err) => Err(ForkError::Handoff(errno(err))),
+			}
+		}
+		Err(err) => {
+			let _ = unistd::close(read);
+			let _ = unistd::close(write);
+			Err(err)
+		}
+	}
+}
+
+/// [`fork_with`] with namespaces.
+#[cfg(target_os = "linux")]
+fn fork_namespaces(options: &ForkOptions) -> Result<ForkResult, ForkError> {
+	let write_maps = !options.uid_map.is_empty() || !options.gid_map.is_empty();
+	if write_maps && !options.namespaces.contains(Namespaces::USER) {
+		return Err(ForkError::Namespace(Errno::EINVAL));
+	}
+	if !pidfd::is_supported() {
+		return Err(ForkError::Pidfd(Errno::ENOSYS));
+	}
+	let (ready_read, ready_write) =
+		UnixDatagram::pair().map_err(|err| ForkError::Pipe(io_errno(&err)))?;
+	handlers::prepare();
+	// the child in a new pid namespace isn't forked by `basic_fork`, so is covered by this instead
+	children::fork_start();
+	let ret = fork_namespaces_(options, ready_read, ready_write);
+	// in both us and the child
+	children::fork_end();
+	if let Ok(ForkResult::Child) = ret {
+		handlers::child();
+	} else {
+		handlers::parent();
+	}
+	ret
+}
+
+/// [`fork_with`] with namespaces, once the socket pair to communicate with the child is created. The intermediate child `unshare`s, and is the child unless there's a new pid namespace, in which case it forks the child into one as a sibling, reports its pid, forks the watchdog, and exits.
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_lines)]
+fn fork_namespaces_(
+	options: &ForkOptions, ready_read: UnixDatagram, ready_write: UnixDatagram,
+) -> Result<ForkResult, ForkError> {
+	let parent = unistd::getpid();
+	let new_pid = options.namespaces.contains(Namespaces::PID);
+	let write_maps = !options.uid_map.is_empty() || !options.gid_map.is_empty();
+	let intermediate = match basic_fork(false).map_err(|err| ForkError::Fork(errno(err)))? {
+		ForkResult::Parent(child) => child,
+		ForkResult::Child => {
+			drop(ready_read);
+			let ret = (|| {
+				if write_maps {
+					// so we don't wait forever on a parent that's died
+					let ret = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+					if ret != 0 || unistd::getppid() != parent {
+						signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
+						loop {}
+					}
+				}
+				// a new pid namespace is instead created by `fork_sibling`, so the watchdog's outside it
+				let ret = unsafe { libc::unshare((options.namespaces - Namespaces::PID).bits()) };
+				let _ = Errno::result(ret).map_err(|err| ForkError::Namespace(errno(err)))?;
+				if write_maps {
+					// the parent writes our id maps, then tells us to carry on
+					let _ = ready_write
+						.send(&[0])
+						.map_err(|err| ForkError::Handoff(io_errno(&err)))?;
+					let _ = ready_write
+						.recv(&mut [0])
+						.map_err(|err| ForkError::Handoff(io_errno(&err)))?;
+					let _ = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, 0) };
+				}
+				if !new_pid {
+					return fork_watchdog(&ready_write, None).map(|()| true);
+				}
+				let (sibling, pidfd) = match pidfd::fork_sibling(libc::CLONE_NEWPID)
+					.map_err(|err| ForkError::Fork(errno(err)))?
+				{
+					Some(sibling) => sibling,
+					None => return Ok(true),
+				};
+				let pid = sibling.as_raw().to_ne_bytes();
+				let ret = ready_write
+					.send(&pid)
+					.map_err(|err| ForkError::Handoff(io_errno(&err)))
+					.and_then(|_| fork_watchdog(&ready_write, Some(pidfd)));
+				if ret.is_err() {
+					let _ = pidfd::send_signal(pidfd, Some(signal::SIGKILL));
+				}
+				ret.map(|()| false)
+			})();
+			match ret {
+				Ok(true) => return Ok(ForkResult::Child),
+				Ok(false) => unsafe { libc::_exit(0) },
+				Err(err) => {
+					let _ = ready_write.send(&err.to_bytes());
+					unsafe { libc::_exit(1) }
+				}
+			}
+		}
+	};
+	drop(ready_write);
+	let mut sibling = None;
+	let guard_write = (|| {
+		let mut msg = [0; 5];
+		if write_maps {
+			receive_msg(&ready_read, &mut msg, 1)?;
+			write_id_maps(intermediate.pid, options)
+				.map_err(|err| ForkError::Namespace(io_errno(&err)))?;
+			let _ = ready_read
+				.send(&[0])
+				.map_err(|err| ForkError::Handoff(io_errno(&err)))?;
+		}
+		if new_pid {
+			receive_msg(&ready_read, &mut msg, 4)?;
+			let pid = Pid::from_raw(i32::from_ne_bytes([msg[0], msg[1], msg[2], msg[3]]));
+			sibling = Some(ChildHandle {
+				pid,
+				owns: None,
+				tracked: children::insert(pid),
+				status: Mutex::new(None),
+				received: false,
+			});
+		}
+		receive_guard(&ready_read)
+	})();
+	drop(ready_read);
+	let kill = |child: &ChildHandle| {
+		let _ = signal::kill(child.pid, signal::SIGKILL);
+		let _ = ChildHandle::wait_(child.pid);
+		child.set_reaped();
+	};
+	let guard_write = match guard_write {
+		Ok(guard_write) => guard_write,
+		Err(err) => {
+			sibling
+				.iter()
+				.chain(iter::once(&intermediate))
+				.for_each(kill);
+			return Err(err);
+		}
+	};
+	let mut child = match sibling {
+		Some(sibling) => {
+			// it exits once it's handed off the sibling
+			let _ = intermediate.wait();
+			sibling
+		}
+		None => intermediate,
+	};
+	// the child is yet to be reaped, so its pid can't have been reused
+	let pidfd = match pidfd::open(child.pid) {
+		Ok(pidfd) => pidfd,
+		Err(err) => {
+			let _ = unistd::close(guard_write);
+			kill(&child);
+			return Err(ForkError::Pidfd(errno(err)));
+		}
+	};
+	child.owns = Some(Handle {
+		state: AtomicU8::new(0),
+		retainer_killed: AtomicBool::new(false),
+		guard_write: Some(guard_write),
+		pidfd: Some(pidfd),
+		exit_pipe: Mutex::new(None),
+	});
+	Ok(ForkResult::Parent(child))
+}
+
+/// In the parent: write the uid and gid maps of a child that's `unshare`d its user namespace.
+#[cfg(target_os = "linux")]
+fn write_id_maps(pid: Pid, options: &ForkOptions) -> io::Result<()> {
+	let format = |map: &[IdMap]| {
+		use fmt::Write;
+		map.iter().fold(String::new(), |mut lines, id| {
+			writeln!(lines, "{} {} {}", id.inside, id.outside, id.count).unwrap();
+			lines
+		})
+	};
+	if !options.uid_map.is_empty() {
+		fs::write(format!("/proc/{}/uid_map", pid), format(&options.uid_map))?;
+	}
+	if !options.gid_map.is_empty() {
+		if !unistd::geteuid().is_root() {
+			fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+		}
+		fs::write(format!("/proc/{}/gid_map", pid), format(&options.gid_map))?;
+	}
+	Ok(())
+}
+
+/// Options for [`daemonize`]
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct DaemonOptions {
+	/// Directory for the daemon to change to, so it doesn't keep a filesystem busy. Defaults to `/`.
+	pub dir: PathBuf,
+	/// The daemon's file mode creation mask. Defaults to empty, i.e. `0`.
+	pub umask: stat::Mode,
+	/// File for the daemon's stdout to append to, created if need be. `None`, the default, is `/dev/null`.
+	pub stdout: Option<PathBuf>,
+	/// File for the daemon's stderr to append to, created if need be. `None`, the default, is `/dev/null`.
+	pub stderr: Option<PathBuf>,
+	/// Fds other than stdio for the daemon to keep. All others are closed.
+	pub keep_fds: Vec<Fd>,
+}
+#[cfg(unix)]
+impl Default for DaemonOptions {
+	fn default() -> Self {
+		Self {
+			dir: PathBuf::from("/"),
+			umask: stat::Mode::empty(),
+			stdout: None,
+			stderr: None,
+			keep_fds: Vec::new(),
+		}
+	}
+}
+
+/// Start a daemon, as described in [daemon(7)](http://man7.org/linux/man-pages/man7/daemon.7.html): a process orphaned as with [`fork(true)`](fork), whose intermediate parent detaches from our session and controlling terminal with `setsid` before forking it, so that not being the session leader it can't acquire a terminal. It resets the signals we handle to their default dispositions and unblocks all signals, changes directory and umask, redirects stdin from `/dev/null` and stdout and stderr to `/dev/null` or log files, and closes all other fds not in [`DaemonOptions::keep_fds`]. Signals we ignore, like `SIGPIPE` which std ignores, are left ignored.
+///
+/// The daemon reports back to us over a pipe once it's set up, so a failure at any stage is returned here as a [`ForkError`] – [`ForkError::Daemon`] for changing directory or redirecting stdio – and the daemon exits rather than returning. A launcher can thus exit with a status reflecting whether the daemon started. The log files are opened before changing directory, so relative paths are relative to our current directory.
+///
+/// The returned [`ChildHandle`] doesn't own the daemon, which is reparented to init: it isn't killed on our termination and can't be waited on.
+///
+/// # Example
+/// ```no_run
+/// use palaver::process::*;
+/// use std::process;
+///
+/// let options = DaemonOptions {
+///     stderr: Some("daemon.log".into()),
+///     ..DaemonOptions::default()
+/// };
+/// match daemonize(&options) {
+///     Ok(ForkResult::Parent(_)) => process::exit(0),
+///     Ok(ForkResult::Child) => {
+///         // run the daemon
+///     }
+///     Err(err) => {
+///         eprintln!("{}", err);
+///         process::exit(1);
+///     }
+/// }
+/// ```
+#[cfg(unix)]
+pub fn daemonize(options: &DaemonOptions) -> Result<ForkResult, ForkError> {
+	// the daemon reports a failure to set up, and otherwise just closes its end
+	let (read, write) =
+		file::pipe(fcntl::OFlag::O_CLOEXEC).map_err(|err| ForkError::Pipe(errno(err)))?;
+	// sorted, so the daemon can close the fds in between them without allocating
+	let mut keep_fds = options.keep_fds.clone();
+	keep_fds.extend_from_slice(&[
+		libc::STDIN_FILENO,
+		libc::STDOUT_FILENO,
+		libc::STDERR_FILENO,
+		write,
+	]);
+	keep_fds.sort_unstable();
+	keep_fds.dedup();
+	match with_handlers(|| fork_(true, true, true)) {
+		Ok(ForkResult::Child) => {
+			let _ = unistd::close(read);
+			if let Err(err) = daemon_setup(options, &keep_fds) {
+				let _ = unistd::write(write, &err.to_bytes());
+				unsafe { libc::_exit(1) }
+			}
+			let _ = unistd::close(write);
+			Ok(ForkResult::Child)
+		}
+		Ok(ForkResult::Parent(child)) => {
+			let _ = unistd::close(write);
+			let mut msg = [0; 5];
+			let ret = unistd::read(read, &mut msg);
+			let _ = unistd::close(read);
+			match ret {
+				Ok(0) => Ok(ForkResult::Parent(child)),
+				Ok(5) => Err(ForkError::from_bytes(msg)),
+				Ok(_) => Err(ForkError::Handoff(Errno::EBADMSG)),
+				Err(err) => Err(ForkError::Handoff(errno(err))),
+			}
+		}
+		Err(err) => {
+			let _ = unistd::close(read);
+			let _ = unistd::close(write);
+			Err(err)
+		}
+	}
+}
+
+/// In the daemon: reset signals, redirect stdio, and close all fds other than the sorted `keep_fds`.
+#[cfg(unix)]
+fn daemon_setup(options: &DaemonOptions, keep_fds: &[Fd]) -> Result<(), ForkError> {
+	let new = signal::SigAction::new(
+		signal::SigHandler::SigDfl,
+		signal::SaFlags::empty(),
+		signal::SigSet::empty(),
+	);
+	for signal in Signal::iterator() {
+		if signal == Signal::SIGKILL || signal == Signal::SIGSTOP {
+			continue;
+		}
+		let mut old = mem::MaybeUninit::<libc::sigaction>::uninit();
+		let ret = unsafe { libc::sigaction(signal as libc::c_int, ptr::null(), old.as_mut_ptr()) };
+		let _ = Errno::result(ret).map_err(|err| ForkError::Signal(errno(err)))?;
+		let handler = unsafe { old.assume_init() }.sa_sigaction;
+		if handler != libc::SIG_DFL && handler != libc::SIG_IGN {
+			let _ = unsafe { signal::sigaction(signal, &new) }
+				.map_err(|err| ForkError::Signal(errno(err)))?;
+		}
+	}
+	signal::pthread_sigmask(
+		signal::SigmaskHow::SIG_SETMASK,
+		Some(&signal::SigSet::empty()),
+		None,
+	)
+	.map_err(|err| ForkError::Signal(errno(err)))?;
+	let daemon = |err| ForkError::Daemon(errno(err));
+	let open = |path: Option<&PathBuf>, flags| {
+		let path = path.map_or(Path::new("/dev/null"), PathBuf::as_path);
+		fcntl::open(path, flags, stat::Mode::from_bits_truncate(0o666)).map_err(daemon)
+	};
+	let append = fcntl::OFlag::O_WRONLY | fcntl::OFlag::O_APPEND | fcntl::OFlag::O_CREAT;
+	// opened lowest first, so each is at or above the stdio fd it's moved to, and won't be overwritten by an earlier move
+	let stdio = [
+		open(None, fcntl::OFlag::O_RDONLY)?,
+		open(options.stdout.as_ref(), append)?,
+		open(options.stderr.as_ref(), append)?,
+	];
+	unistd::chdir(&options.dir).map_err(daemon)?;
+	let _ = stat::umask(options.umask);
+	for (to, &from) in (0..).zip(&stdio) {
+		if from != to {
+			file::move_fd(from, to, Some(fcntl::FdFlag::empty()), true).map_err(daemon)?;
+		}
+	}
+	let mut low = 0;
+	for high in keep_fds.iter().map(|&fd| Some(fd)).chain(iter::once(None)) {
+		close_fds(low, high);
+		low = high.map_or(low, |high| high + 1);
+	}
+	Ok(())
+}
+
+/// Close the fds from `low` up to but excluding `high`, or all of them from `low` if `high` is `None`.
+#[cfg(unix)]
+fn close_fds(low: Fd, high: Option<Fd>) {
+	match high {
+		Some(high) if high <= low => return,
+		_ => (),
+	}
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	{
+		// Linux 5.9+
+		let first: libc::c_uint = low.try_into().unwrap();
+		let last = high.map_or(libc::c_uint::MAX, |high| (high - 1).try_into().unwrap());
+		if unsafe { libc::syscall(libc::SYS_close_range, first, last, 0) } == 0 {
+			return;
+		}
+	}
+	let high = high.unwrap_or_else(|| {
+		rlimit::get(rlimit::Resource::RLIMIT_NOFILE)
+			.ok()
+			.and_then(|limit| limit.soft)
+			.and_then(|soft| soft.try_into().ok())
+			.unwrap_or(1024)
+	});
+	for fd in low..high {
+		let _ = unistd::close(fd);
+	}
+}
+
+/// A pid file, created and locked with [`PidFile::create`], and removed on drop.
+///
+/// The lock is an [OFD lock](https://www.gnu.org/software/libc/manual/html_node/Open-File-Description-Locks.html) on Linux, and `flock` elsewhere, so it belongs to the open file description rather than the process: it's shared with children that inherit the fd, including a daemon started with [`daemonize`] if the fd is in [`DaemonOptions::keep_fds`], and is released once every process holding it has closed it or died. A file that isn't locked is thus stale, its owner having died without removing it, and is taken over.
+///
+/// The file is removed on drop only by the process whose pid it holds. A daemon should call [`PidFile::update`] to write its own pid, while the launcher should exit without dropping it, or release it with [`into_raw_fd`](IntoRawFd::into_raw_fd).
+///
+/// # Example
+/// ```no_run
+/// use palaver::process::*;
+/// use std::{os::unix::io::AsRawFd, process};
+///
+/// let mut pid_file = match PidFile::create("/run/example.pid") {
+///     Ok(pid_file) => pid_file,
+///     Err(err) => {
+///         eprintln!("{}", err);
+///         process::exit(1);
+///     }
+/// };
+/// let options = DaemonOptions {
+///     keep_fds: vec![pid_file.as_raw_fd()],
+///     ..DaemonOptions::default()
+/// };
+/// match daemonize(&options).unwrap() {
+///     ForkResult::Parent(_) => process::exit(0),
+///     ForkResult::Child => {
+///         pid_file.update().unwrap();
+///         // run the daemon, removing the pid file on return
+///     }
+/// }
+/// ```
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct PidFile {
+	path: PathBuf,
+	fd: Fd,
+	pid: Pid,
+}
+#[cfg(unix)]
+impl PidFile {
+	/// Create and lock the pid file at `path`, and write our pid to it. Fails with [`PidFileError::Locked`] if another `PidFile` has it locked.
+	pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, PidFileError> {
+		let path = path.as_ref();
+		let sys = |err| PidFileError::Sys(errno(err));
+		let fd = loop {
+			let fd = fcntl::open(
+				path,
+				fcntl::OFlag::O_RDWR | fcntl::OFlag::O_CREAT | fcntl::OFlag::O_CLOEXEC,
+				stat::Mode::from_bits_truncate(0o644),
+			)
+			.map_err(sys)?;
+			if let Err(err) = Self::lock(fd) {
+				let err = match err {
+					Error::Sys(Errno::EAGAIN) | Error::Sys(Errno::EACCES) => {
+						PidFileError::Locked(Self::read_pid(fd))
+					}
+					err => sys(err),
+				};
+				let _ = unistd::close(fd);
+				return Err(err);
+			}
+			// the owner might have removed the file between us opening and locking it
+			let ours = stat::fstat(fd);
+			let current = stat::stat(path);
+			match (ours, current) {
+				(Ok(ours), Ok(current))
+					if (ours.st_dev, ours.st_ino) == (current.st_dev, current.st_ino) =>
+				{
+					break fd
+				}
+				(Ok(_), Ok(_)) | (Ok(_), Err(Error::Sys(Errno::ENOENT))) => {
+					let _ = unistd::close(fd);
+				}
+				(Err(err), _) | (_, Err(err)) => {
+					let _ = unistd::close(fd);
+					return Err(sys(err));
+				}
+			}
+		};
+		let mut pid_file = Self {
+			path: path.to_owned(),
+			fd,
+			pid: unistd::getpid(),
+		};
+		pid_file.update().map_err(sys)?;
+		Ok(pid_file)
+	}
+
+	/// Write our pid to the file, replacing that of the process that created it. To be called in a child, typically a daemon, that takes it over.
+	pub fn update(&mut self) -> nix::Result<()> {
+		use io::Write;
+		let pid = unistd::getpid();
+		let mut buf = [0; 16];
+		let len = {
+			let mut cursor = &mut buf[..];
+			writeln!(cursor, "{}", pid).unwrap();
+			16 - cursor.len()
+		};
+		unistd::ftruncate(self.fd, 0)?;
+		if nix::sys::uio::pwrite(self.fd, &buf[..len], 0)? != len {
+			return Err(Error::Sys(Errno::EIO));
+		}
+		self.pid = pid;
+		Ok(())
+	}
+
+	/// The pid written to the file.
+	pub fn pid(&self) -> Pid {
+		self.pid
+	}
+
+	/// The path of the file.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Lock the whole of `fd` exclusively, without blocking.
+	fn lock(fd: Fd) -> nix::Result<()> {
+		#[cfg(any(target_os = "android", target_os = "linux"))]
+		{
+			let mut lock: libc::flock = unsafe { mem::zeroed() };
+			lock.l_type = libc::F_WRLCK.try_into().unwrap();
+			lock.l_whence = libc::SEEK_SET.try_into().unwrap();
+			// nix's `FcntlArg::F_OFD_SETLK` is unimplemented
+			match Errno::result(unsafe { libc::fcntl(fd, libc::F_OFD_SETLK, &lock) }) {
+				// Linux 3.15+
+				Err(Error::Sys(Errno::EINVAL)) => (),
+				ret => return ret.map(drop),
+			}
+		}
+		fcntl::flock(fd, fcntl::FlockArg::LockExclusiveNonblock)
+	}
+
+	/// The pid written to `fd`, if any.
+	fn read_pid(fd: Fd) -> Option<Pid> {
+		let mut buf = [0; 16];
+		let len = nix::sys::uio::pread(fd, &mut buf, 0).ok()?;
+		let pid = std::str::from_utf8(&buf[..len]).ok()?.trim().parse().ok()?;
+		Some(Pid::from_raw(pid))
+	}
+}
+#[cfg(unix)]
+impl AsRawFd for PidFile {
+	/// The locked fd, owned by the `PidFile` and closed when it's dropped.
+	fn as_raw_fd(&self) -> Fd {
+		self.fd
+	}
+}
+#[cfg(unix)]
+impl IntoRawFd for PidFile {
+	/// Release the pid file without removing it, returning the locked fd. The lock is held until it, and any copies inherited by children, are closed.
+	fn into_raw_fd(self) -> Fd {
+		let fd = self.fd;
+		mem::forget(self);
+		fd
+	}
+}
+#[cfg(unix)]
+impl Drop for PidFile {
+	/// Remove the file if it holds our pid, and close it, releasing our hold on the lock. The pid is read from the file, as a child sharing it might have [`update`](PidFile::update)d it since.
+	fn drop(&mut self) {
+		if Self::read_pid(self.fd) == Some(unistd::getpid()) {
+			let _ = unistd::unlink(&self.path);
+		}
+		let _ = unistd::close(self.fd);
+	}
+}
+
+/// An error from [`PidFile::create`].
+#[cfg(unix)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PidFileError {
+	/// The pid file is locked by a running process: the one with this pid, unless it's yet to write it.
+	Locked(Option<Pid>),
+	/// Opening, locking or writing the pid file failed.
+	Sys(Errno),
+}
+#[cfg(unix)]
+impl fmt::Display for PidFileError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Locked(Some(pid)) => write!(f, "pid file locked by running process {}", pid),
+			Self::Locked(None) => write!(f, "pid file locked by running process"),
+			Self::Sys(errno) => write!(f, "pid file failed: {}", errno.desc()),
+		}
+	}
+}
+#[cfg(unix)]
+impl std::error::Error for PidFileError {}
+
+/// The function a [`ForkServer`] child runs, given the argument passed to [`ForkServer::spawn`]. The child exits with status 0 if it returns, and 101 if it panics.
+///
+/// The child is forked with a raw `clone3`, which leaves libc's cached TID stale, so it should avoid `raise`, `abort` (including by panicking with `panic = "abort"`) and `pthread_kill`.
+#[cfg(target_os = "linux")]
+pub type Entry = fn(&[u8]);
+
+/// The largest argument that can be passed to [`ForkServer::spawn`].
+#[cfg(target_os = "linux")]
+pub const FORK_SERVER_MAX_ARG: usize = 64 * 1024;
+
+/// A fork server, or zygote: a process forked early on, while we're still single-threaded, that forks children on our behalf thereafter.
+///
+/// Forking a multithreaded process copies only the forking thread, so locks held by other threads, including the allocator's, are never released in the child. A `ForkServer` sidesteps this: it's forked before any other threads are spawned, stays single-threaded, and on [`spawn`](ForkServer::spawn) forks a child that runs one of the [`Entry`] functions it was started with. The child is created with `CLONE_PARENT`, so it's our child rather than the server's, and is returned as a [`ChildHandle`] that can be signalled, waited on, and kills it on drop, as with [`fork`]. It's also killed on our death.
+///
+/// The child starts from the server's state, i.e. ours at the point the server was started, rather than that of the requesting thread; anything it needs beyond that goes in the argument.
+///
+/// Linux 5.3+ only, as it relies on `clone3` and pidfds.
+///
+/// # Example
+/// ```no_run
+/// use palaver::process::*;
+///
+/// fn hello(arg: &[u8]) {
+///     println!("hello {}", String::from_utf8_lossy(arg));
+/// }
+///
+/// fn main() {
+///     let server = ForkServer::new(&[("hello", hello)]).unwrap();
+///     // spawn threads etc
+///     let child = server.spawn("hello", b"world").unwrap();
+///     assert!(matches!(child.wait().unwrap(), WaitStatus::Exited(0)));
+/// }
+/// ```
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct ForkServer {
+	_server: ChildHandle, // killed on drop, if it hasn't already exited
+	sock: Mutex<Fd>,
+	entries: Vec<&'static str>,
+}
+#[cfg(target_os = "linux")]
+impl ForkServer {
+	/// Start a fork server, whose children can run any of `entries`, identified by name. This must be called from the main thread, as the children are killed on the death of the thread that started it, and should be called before any other threads are spawned.
+	///
+	/// Fails with [`ForkError::Server`] with `EINVAL` if called from another thread, or `ENOSYS` if pidfds aren't available.
+	pub fn new(entries: &[(&'static str, Entry)]) -> Result<Self, ForkError> {
+		use nix::sys::socket::{AddressFamily, SockFlag, SockType};
+		if unistd::gettid() != unistd::getpid() {
+			return Err(ForkError::Server(Errno::EINVAL));
+		}
+		if !pidfd::is_supported() {
+			return Err(ForkError::Server(Errno::ENOSYS));
+		}
+		let (sock, server_sock) = nix::sys::socket::socketpair(
+			AddressFamily::Unix,
+			SockType::SeqPacket,
+			None,
+			SockFlag::SOCK_CLOEXEC,
+		)
+		.map_err(|err| ForkError::Pipe(errno(err)))?;
+		let server = match fork(false) {
+			Ok(ForkResult::Parent(server)) => server,
+			Ok(ForkResult::Child) => {
+				let _ = unistd::close(sock);
+				Self::serve(server_sock, entries)
+			}
+			Err(err) => {
+				let _ = unistd::close(sock);
+				let _ = unistd::close(server_sock);
+				return Err(err);
+			}
+		};
+		let _ = unistd::close(server_sock);
+		Ok(Self {
+			_server: server,
+			sock: Mutex::new(sock),
+			entries: entries.iter().map(|&(name, _)| name).collect(),
+		})
+	}
+
+	/// Have the server fork a child that runs the entry function named `entry` with `arg`, which can be up to [`FORK_SERVER_MAX_ARG`] bytes. This can be called from any thread.
+	///
+	/// Fails with [`ForkError::Server`] with `ENOENT` if there's no such entry function, `EMSGSIZE` if `arg` is too long, or `EPIPE` if the server has died.
+	///
+	/// # Panics
+	///
+	/// Panics if another thread panicked while spawning.
+	pub fn spawn(&self, entry: &str, arg: &[u8]) -> Result<ChildHandle, ForkError> {
+		let index: u32 = self
+			.entries
+			.iter()
+			.position(|&name| name == entry)
+			.ok_or(ForkError::Server(Errno::ENOENT))?
+			.try_into()
+			.unwrap();
+		if arg.len() > FORK_SERVER_MAX_ARG {
+			return Err(ForkError::Server(Errno::EMSGSIZE));
+		}
+		let msg = [&index.to_ne_bytes()[..], arg].concat();
+		let sock = self.sock.lock().unwrap();
+		// so reapers of other children leave the child be till it's tracked
+		children::fork_start();
+		let ret = (|| {
+			let _ = socket::send_fds(*sock, &msg, &[], false).map_err(|err| match errno(err) {
+				Errno::ECONNRESET => ForkError::Server(Errno::EPIPE),
+				err => ForkError::Server(err),
+			})?;
+			let mut reply = [0; 5];
+			let mut fds = [0; 1];
+			let received = socket::recv_fds(*sock, &mut reply, &mut fds)
+				.map_err(|err| ForkError::Server(errno(err)))?;
+			match (received.len, received.fds) {
+				(4, 1) => Ok((
+					Pid::from_raw(i32::from_ne_bytes([reply[0], reply[1], reply[2], reply[3]])),
+					fds[0],
+				)),
+				(5, 0) => Err(ForkError::from_bytes(reply)),
+				(0, 0) => Err(ForkError::Server(Errno::EPIPE)),
+				(_, fds_) => {
+					fds[..fds_].iter().for_each(|&fd| {
+						let _ = unistd::close(fd);
+					});
+					Err(ForkError::Server(Errno::EBADMSG))
+				}
+			}
+		})();
+		let ret = ret.map(|(pid, pidfd)| ChildHandle {
+			pid,
+			owns: Some(Handle {
+				state: AtomicU8::new(0),
+				retainer_killed: AtomicBool::new(false),
+				guard_write: None,
+				pidfd: Some(pidfd),
+				exit_pipe: Mutex::new(None),
+			}),
+			tracked: children::insert(pid),
+			status: Mutex::new(None),
+			received: false,
+		});
+		children::fork_end();
+		ret
+	}
+
+	/// The server's loop, which exits once our end of `sock` is closed.
+	fn serve(sock: Fd, entries: &[(&'static str, Entry)]) -> ! {
+		let parent = unistd::getppid();
+		let mut msg = vec![0; 4 + FORK_SERVER_MAX_ARG];
+		loop {
+			let len = match socket::recv_fds(sock, &mut msg, &mut []) {
+				Ok(received) if received.len >= 4 => received.len,
+				Ok(_) => unsafe { libc::_exit(0) },
+				Err(Error::Sys(Errno::EINTR)) => continue,
+				Err(_) => unsafe { libc::_exit(1) },
+			};
+			let index: usize = u32::from_ne_bytes([msg[0], msg[1], msg[2], msg[3]])
+				.try_into()
+				.unwrap();
+			let entry = entries[index].1;
+			// we're single-threaded, so can bypass libc's fork
+			handlers::prepare();
+			let ret = pidfd::fork_sibling(0);
+			if let Ok(None) = ret {
+				handlers::child();
+				// as with `fork_pdeathsig`, our parent being that of the server
+				let ret = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+				if ret != 0 || unistd::getppid() != parent {
+					signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
+					loop {}
+				}
+				let _ = unistd::close(sock);
+				if panic::catch_unwind(|| entry(&msg[4..len])).is_err() {
+					unsafe { libc::_exit(101) }
+				}
+				std::process::exit(0);
+			}
+			handlers::parent();
+			let ret = match ret {
+				Ok(Some((pid, pidfd))) => {
+					let ret = socket::send_fds(sock, &pid.as_raw().to_ne_bytes(), &[pidfd], false);
+					let _ = unistd::close(pidfd);
+					ret
+				}
+				Err(err) => {
+					socket::send_fds(sock, &ForkError::Fork(errno(err)).to_bytes(), &[], false)
+				}
+				Ok(None) => unreachable!(),
+			};
+			if ret.is_err() {
+				unsafe { libc::_exit(1) }
+			}
+		}
+	}
+}
+#[cfg(target_os = "linux")]
+impl Drop for ForkServer {
+	fn drop(&mut self) {
+		// the server exits on seeing this
+		unistd::close(*self.sock.get_mut().unwrap()).unwrap();
+	}
+}
+
+/// A builder for spawning a program with [`fork`], or where possible the cheaper `vfork`, then `execve`, or `fexecve` for an in-memory binary, returning a [`ChildHandle`] with the same guarantees: it's immune to PID race conditions, kills the child on drop, and the child's killed on our death.
+///
+/// Unlike [`std::process::Command`] the program isn't searched for in `PATH`. The environment is ours as of [`spawn`](Spawn::spawn), with the changes made here applied on top. Everything is prepared before forking, so the child doesn't allocate and it's safe to spawn from a multithreaded program. Failures in the child, including of the `exec` itself, are reported back over a `CLOEXEC` pipe and returned from [`spawn`](Spawn::spawn) as a [`ForkError`].
+///
+/// # Example
+/// ```no_run
+/// use palaver::process::*;
+///
+/// let child = Spawn::new("/bin/sh")
+///     .args(&["-c", "echo $GREETING"])
+///     .env_clear()
+///     .env("GREETING", "hello")
+///     .spawn()
+///     .unwrap();
+/// assert!(matches!(child.wait().unwrap(), WaitStatus::Exited(0)));
+/// ```
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct Spawn {
+	program: Program,
+	args: Vec<CString>,
+	vars: Vec<(OsString, Option<OsString>)>, // set, or removed with None
+	env_clear: bool,
+	fds: Vec<(Fd, Fd)>,
+	dir: Option<CString>,
+	process_group: Option<Pid>,
+	reset_signals: bool,
+	nul: bool, // whether an arg or path had an interior nul, for spawn to fail with
+}
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+enum Program {
+	Path(CString),
+	Fd(Fd),
+}
+#[cfg(unix)]
+impl Spawn {
+	/// Spawn the program at `path`, with it as the first arg.
+	pub fn new<S: AsRef<OsStr>>(path: S) -> Self {
+		let mut nul = false;
+		let path = cstring(path.as_ref(), &mut nul);
+		Self {
+			program: Program::Path(path.clone()),
+			args: vec![path],
+			vars: Vec::new(),
+			env_clear: false,
+			fds: Vec::new(),
+			dir: None,
+			process_group: None,
+			reset_signals: true,
+			nul,
+		}
+	}
+	/// Spawn the program open as `fd`, for example an in-memory binary from [`file::memfd_create`], with `fexecve`, or where that's unavailable by its path in `/dev/fd`. There are no args to start with, so the first should be set with [`arg`](Spawn::arg).
+	pub fn from_fd(fd: Fd) -> Self {
+		Self {
+			program: Program::Fd(fd),
+			args: Vec::new(),
+			vars: Vec::new(),
+			env_clear: false,
+			fds: Vec::new(),
+			dir: None,
+			process_group: None,
+			reset_signals: true,
+			nul: false,
+		}
+	}
+	/// Add an arg.
+	pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+		let arg = cstring(arg.as_ref(), &mut self.nul);
+		self.args.push(arg);
+		self
+	}
+	/// Add args.
+	pub fn args<I, S>(&mut self, args: I) -> &mut Self
+	where
+		I: IntoIterator<Item = S>,
+		S: AsRef<OsStr>,
+	{
+		for arg in args {
+			let _ = self.arg(arg);
+		}
+		self
+	}
+	/// Set an environment variable, replacing any existing value.
+	pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, value: V) -> &mut Self {
+		self.vars.retain(|(key_, _)| key_ != key.as_ref());
+		self.vars
+			.push((key.as_ref().to_owned(), Some(value.as_ref().to_owned())));
+		self
+	}
+	/// Remove an environment variable.
+	pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+		self.vars.retain(|(key_, _)| key_ != key.as_ref());
+		self.vars.push((key.as_ref().to_owned(), None));
+		self
+	}
+	/// Clear the environment, rather than starting from ours, along with the variables set so far.
+	pub fn env_clear(&mut self) -> &mut Self {
+		self.vars.clear();
+		self.env_clear = true;
+		self
+	}
+	/// Have our fd `from` be `to` in the child, without `FD_CLOEXEC`. Any number of fds can be mapped, including onto each other. Other fds are inherited by the child as usual, i.e. unless they're `CLOEXEC`.
+	pub fn fd(&mut self, from: Fd, to: Fd) -> &mut Self {
+		self.fds.retain(|&(_, to_)| to_ != to);
+		self.fds.push((from, to));
+		self
+	}
+	/// Change the child's directory to `dir`.
+	pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+		self.dir = Some(cstring(dir.as_ref().as_os_str(), &mut self.nul));
+		self
+	}
+	/// Move the child into the process group `pgroup`, or with `0` a new group of its own, with `setpgid`.
+	pub fn process_group(&mut self, pgroup: Pid) -> &mut Self {
+		self.process_group = Some(pgroup);
+		self
+	}
+	/// Whether to clear the child's signal mask and reset the signals we ignore to their default dispositions, as `exec` doesn't. Defaults to `true`.
+	pub fn reset_signals(&mut self, reset: bool) -> &mut Self {
+		self.reset_signals = reset;
+		self
+	}
+
+	/// Fork and exec the program.
+	///
+	/// On Linux when pidfds are available and we're the main thread, this uses `clone` with `CLONE_VM | CLONE_VFORK` rather than [`fork`], so the cost doesn't grow with our memory use, as copying the page tables does. The child is set up as by `fork` – a pidfd and `PR_SET_PDEATHSIG` – but the handlers registered with [`atfork`] aren't run, as with `posix_spawn`. Otherwise it falls back to [`fork`]; `posix_spawn` isn't used on other platforms, so there the cost does grow with our memory use.
+	///
+	/// Fails with [`ForkError::Exec`] with `EINVAL` if an arg, variable or path contained a nul byte, `EBADF` if a mapped fd isn't open, or the error from `chdir` or `exec` in the child, or `EIO` if setting it up panicked.
+	pub fn spawn(&self) -> Result<ChildHandle, ForkError> {
+		let exec = |err| ForkError::Exec(errno(err));
+		let mut nul = self.nul;
+		// ours as of now, with the changes applied on top
+		let mut vars = if self.env_clear {
+			Vec::new()
+		} else {
+			std::env::vars_os().collect::<Vec<_>>()
+		};
+		for (key, value) in &self.vars {
+			vars.retain(|(key_, _)| key_ != key);
+			if let Some(value) = value {
+				vars.push((key.clone(), value.clone()));
+			}
+		}
+		let vars = vars
+			.into_iter()
+			.map(|(mut var, value)| {
+				var.push("=");
+				var.push(value);
+				cstring(&var, &mut nul)
+			})
+			.collect::<Vec<_>>();
+		if nul {
+			return Err(ForkError::Exec(Errno::EINVAL));
+		}
+		// the null-terminated arrays for `execve`, built here as the child can't allocate
+		let args = self
+			.args
+			.iter()
+			.map(|arg| arg.as_ptr())
+			.chain(iter::once(ptr::null()))
+			.collect::<Vec<_>>();
+		let vars = vars
+			.iter()
+			.map(|var| var.as_ptr())
+			.chain(iter::once(ptr::null()))
+			.collect::<Vec<_>>();
+		// duplicated above all the targets, so moving the fds into place can't clobber them, the pipe or the program
+		let min = self.fds.iter().map(|&(_, to)| to + 1).max().unwrap_or(0);
+		let program = match self.program {
+			Program::Path(_) => None,
+			Program::Fd(fd) => Some(fd),
+		};
+		let mut dups = Vec::with_capacity(self.fds.len() + 1);
+		for &fd in program.iter().chain(self.fds.iter().map(|(from, _)| from)) {
+			match fcntl::fcntl(fd, fcntl::FcntlArg::F_DUPFD_CLOEXEC(min)) {
+				Ok(fd) => dups.push(fd),
+				Err(err) => {
+					close_fds_(&dups);
+					return Err(exec(err));
+				}
+			}
+		}
+		let program = match &self.program {
+			Program::Path(path) => Program::Path(path.clone()),
+			#[cfg(any(
+				target_os = "android",
+				target_os = "freebsd",
+				target_os = "fuchsia",
+				target_os = "illumos",
+				target_os = "linux",
+				target_os = "solaris"
+			))]
+			Program::Fd(_) => Program::Fd(dups[0]),
+			#[cfg(not(any(
+				target_os = "android",
+				target_os = "freebsd",
+				target_os = "fuchsia",
+				target_os = "illumos",
+				target_os = "linux",
+				target_os = "solaris"
+			)))]
+			Program::Fd(_) => match file::fd_path(dups[0]) {
+				Ok(path) => Program::Path(cstring(path.as_os_str(), &mut nul)),
+				Err(err) => {
+					close_fds_(&dups);
+					return Err(ForkError::Exec(io_errno(&err)));
+				}
+			},
+		};
+		let mut fds = self
+			.fds
+			.iter()
+			.zip(&dups[dups.len() - self.fds.len()..])
+			.map(|(&(_, to), &from)| (from, to))
+			.collect::<Vec<_>>();
+		#[cfg(target_os = "linux")]
+		let ret = if pidfd::is_supported() && unistd::gettid() == unistd::getpid() {
+			self.spawn_vfork(&program, &mut fds, &args, &vars)
+		} else {
+			self.spawn_fork(&program, &mut fds, &args, &vars, min)
+		};
+		#[cfg(not(target_os = "linux"))]
+		let ret = self.spawn_fork(&program, &mut fds, &args, &vars, min);
+		close_fds_(&dups);
+		ret
+	}
+
+	/// Spawn with [`fork`], the child reporting failure over a `CLOEXEC` pipe.
+	fn spawn_fork(
+		&self, program: &Program, fds: &mut [(Fd, Fd)], args: &[*const libc::c_char],
+		vars: &[*const libc::c_char], min: Fd,
+	) -> Result<ChildHandle, ForkError> {
+		// the child reports a failure to exec, and otherwise the pipe's closed by the exec
+		let (read, write) =
+			file::pipe(fcntl::OFlag::O_CLOEXEC).map_err(|err| ForkError::Pipe(errno(err)))?;
+		let write_ = fcntl::fcntl(write, fcntl::FcntlArg::F_DUPFD_CLOEXEC(min));
+		let _ = unistd::close(write);
+		let write = write_.map_err(|err| {
+			let _ = unistd::close(read);
+			ForkError::Pipe(errno(err))
+		})?;
+		let child = fork(false);
+		if let Ok(ForkResult::Child) = child {
+			// a panic mustn't unwind into the caller's code, nor exit without a report, which would pass for a successful exec
+			let err = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+				self.exec(program, fds, args, vars, None)
+			}))
+			.unwrap_or(ForkError::Exec(Errno::EIO));
+			let _ = unistd::write(write, &err.to_bytes());
+			unsafe { libc::_exit(127) }
+		}
+		let _ = unistd::close(write);
+		let child = match child {
+			Ok(ForkResult::Parent(child)) => child,
+			Ok(ForkResult::Child) => unreachable!(),
+			Err(err) => {
+				let _ = unistd::close(read);
+				return Err(err);
+			}
+		};
+		let mut msg = [0; 5];
+		let ret = loop {
+			match unistd::read(read, &mut msg) {
+				Err(Error::Sys(Errno::EINTR)) => (),
+				ret => break ret,
+			}
+		};
+		let _ = unistd::close(read);
+		match ret {
+			Ok(0) => Ok(child),
+			// the child's exited, and is reaped on drop
+			Ok(5) => Err(ForkError::from_bytes(msg)),
+			Ok(_) => Err(ForkError::Handoff(Errno::EBADMSG)),
+			Err(err) => Err(ForkError::Handoff(errno(err))),
+		}
+	}
+
+	/// Spawn with `clone(CLONE_VM | CLONE_VFORK | CLONE_PIDFD)`. We're suspended till the child execs or exits, and as it shares our memory it can report failure by just writing it to `Vfork::err`.
+	#[cfg(target_os = "linux")]
+	fn spawn_vfork(
+		&self, program: &Program, fds: &mut [(Fd, Fd)], args: &[*const libc::c_char],
+		vars: &[*const libc::c_char],
+	) -> Result<ChildHandle, ForkError> {
+		struct Vfork<'a> {
+			spawn: &'a Spawn,
+			program: &'a Program,
+			fds: &'a mut [(Fd, Fd)],
+			args: &'a [*const libc::c_char],
+			vars: &'a [*const libc::c_char],
+			mask: signal::SigSet,
+			parent: Pid,
+			err: Option<ForkError>,
+		}
+		/// Armed while the child runs our code: a panic mustn't unwind out of it, which would return into our frames, so it's reported as `EIO` and the child exits.
+		struct ExitOnUnwind(*mut Option<ForkError>);
+		impl Drop for ExitOnUnwind {
+			fn drop(&mut self) {
+				unsafe {
+					*self.0 = Some(ForkError::Exec(Errno::EIO));
+					libc::_exit(127)
+				}
+			}
+		}
+		extern "C" fn child(vfork: *mut libc::c_void) -> libc::c_int {
+			let vfork = unsafe { &mut *vfork.cast::<Vfork<'_>>() };
+			let guard = ExitOnUnwind(ptr::NonNull::from(&mut vfork.err).as_ptr());
+			// as with `fork_pdeathsig`
+			let ret = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+			let err = if ret != 0 {
+				ForkError::Fork(Errno::last())
+			} else if unistd::getppid() != vfork.parent {
+				ForkError::Fork(Errno::ESRCH)
+			} else {
+				vfork.spawn.exec(
+					vfork.program,
+					vfork.fds,
+					vfork.args,
+					vfork.vars,
+					Some(&vfork.mask),
+				)
+			};
+			vfork.err = Some(err);
+			mem::forget(guard);
+			127
+		}
+		// enough for the setup and `exec`, including glibc's `fexecve` falling back to `/proc/self/fd`
+		let mut stack = vec![0_u8; 256 * 1024];
+		// no handlers can run in the child, which shares our memory, till it's reset them
+		let mut mask = signal::SigSet::empty();
+		signal::pthread_sigmask(
+			signal::SigmaskHow::SIG_SETMASK,
+			Some(&signal::SigSet::all()),
+			Some(&mut mask),
+		)
+		.map_err(|err| ForkError::Signal(errno(err)))?;
+		let mut vfork = Vfork {
+			spawn: self,
+			program,
+			fds,
+			args,
+			vars,
+			mask,
+			parent: unistd::getpid(),
+			err: None,
+		};
+		let vfork_ptr = ptr::NonNull::from(&mut vfork).as_ptr();
+		let mut pidfd = mem::MaybeUninit::<libc::c_int>::uninit();
+		children::fork_start();
+		let ret = unsafe {
+			libc::clone(
+				child,
+				stack.as_mut_ptr().add(stack.len()).cast(),
+				libc::CLONE_VM | libc::CLONE_VFORK | libc::CLONE_PIDFD | libc::SIGCHLD,
+				vfork_ptr.cast(),
+				pidfd.as_mut_ptr(),
+			)
+		};
+		let ret = Errno::result(ret).map(|pid| {
+			let pid = Pid::from_raw(pid);
+			// set by the kernel on success
+			let pidfd = unsafe { pidfd.assume_init() };
+			ChildHandle {
+				pid,
+				owns: Some(Handle {
+					state: AtomicU8::new(0),
+					retainer_killed: AtomicBool::new(false),
+					guard_write: None,
+					pidfd: Some(pidfd),
+					exit_pipe: Mutex::new(None),
+				}),
+				tracked: children::insert(pid),
+				status: Mutex::new(None),
+				received: false,
+			}
+		});
+		children::fork_end();
+		signal::pthread_sigmask(signal::SigmaskHow::SIG_SETMASK, Some(&mask), None).unwrap();
+		let child = ret.map_err(|err| ForkError::Fork(errno(err)))?;
+		match vfork.err {
+			// the child's exited, and is reaped on drop
+			Some(err) => Err(err),
+			None => Ok(child),
+		}
+	}
+
+	/// In the child: set up then exec, returning the error if either fails. `vforked` is the signal mask to restore if we share our parent's memory, having been spawned with all signals blocked.
+	///
+	/// Nothing here may allocate or panic, as the child may share our memory, and `args` and `vars` are the null-terminated arrays for `execve`.
+	fn exec(
+		&self, program: &Program, fds: &mut [(Fd, Fd)], args: &[*const libc::c_char],
+		vars: &[*const libc::c_char], vforked: Option<&signal::SigSet>,
+	) -> ForkError {
+		if self.reset_signals || vforked.is_some() {
+			let new = signal::SigAction::new(
+				signal::SigHandler::SigDfl,
+				signal::SaFlags::empty(),
+				signal::SigSet::empty(),
+			);
+			for signal in Signal::iterator() {
+				if signal == Signal::SIGKILL || signal == Signal::SIGSTOP {
+					continue;
+				}
+				if !self.reset_signals {
+					// just the handlers, which mustn't run in a child sharing our memory, and are reset by the exec regardless
+					let mut old = mem::MaybeUninit::<libc::sigaction>::uninit();
+					let ret = unsafe {
+						libc::sigaction(signal as libc::c_int, ptr::null(), old.as_mut_ptr())
+					};
+					if ret == 0 && unsafe { old.assume_init() }.sa_sigaction == libc::SIG_IGN {
+						continue;
 					}
-					Ok(ForkResult::Parent(_)) => unsafe { libc::_exit(0) },
-					Err(_) => unsafe { libc::_exit(1) },
 				}
+				let old = unsafe { signal::sigaction(signal, &new) };
+				if let Err(err) = old {
+					return ForkError::Signal(errno(err));
+				}
+			}
+			let mask = match vforked {
+				Some(mask) if !self.reset_signals => *mask,
+				_ => signal::SigSet::empty(),
 			};
-			let exit = child.wait().unwrap();
-			if let WaitStatus::Exited(0) = exit {
-				let pid = Pid::from_raw(i32::max_value()); // TODO!
-				#[cfg(target_os = "freebsd")]
-				let pd = i32::max_value(); // TODO!
-				Ok(ForkResult::Parent(ChildHandle {
-					pid,
-					#[cfg(target_os = "freebsd")]
-					pd,
-					owns: None,
-				}))
-			} else {
-				Err(Error::Sys(Errno::UnknownErrno))
+			let ret = signal::pthread_sigmask(signal::SigmaskHow::SIG_SETMASK, Some(&mask), None);
+			if let Err(err) = ret {
+				return ForkError::Signal(errno(err));
 			}
-		})();
-		if new.handler() != old.handler() {
-			let new2 = unsafe { signal::sigaction(signal::SIGCHLD, &old).unwrap() };
-			assert_eq!(new.handler(), new2.handler());
 		}
+		if let Some(pgroup) = self.process_group {
+			if let Err(err) = unistd::setpgid(Pid::from_raw(0), pgroup) {
+				return ForkError::ProcessGroup(errno(err));
+			}
+		}
+		if let Some(dir) = &self.dir {
+			let ret = unsafe { libc::chdir(dir.as_ptr()) };
+			if let Err(err) = Errno::result(ret) {
+				return ForkError::Exec(errno(err));
+			}
+		}
+		if vforked.is_some() {
+			// `move_fds` panics on failure, which mustn't happen in a child sharing our memory; the sources are all above the targets, so plain `dup2`s do
+			for &(from, to) in fds.iter() {
+				if let Err(err) = unistd::dup2(from, to) {
+					return ForkError::Exec(errno(err));
+				}
+			}
+		} else {
+			file::move_fds(fds, Some(fcntl::FdFlag::empty()), true);
+		}
+		let _ = match program {
+			Program::Path(path) => unsafe {
+				libc::execve(path.as_ptr(), args.as_ptr(), vars.as_ptr())
+			},
+			// `spawn` only passes an fd where there's `fexecve`
+			#[cfg(any(
+				target_os = "android",
+				target_os = "freebsd",
+				target_os = "fuchsia",
+				target_os = "illumos",
+				target_os = "linux",
+				target_os = "solaris"
+			))]
+			Program::Fd(fd) => unsafe { libc::fexecve(*fd, args.as_ptr(), vars.as_ptr()) },
+			#[cfg(not(any(
+				target_os = "android",
+				target_os = "freebsd",
+				target_os = "fuchsia",
+				target_os = "illumos",
+				target_os = "linux",
+				target_os = "solaris"
+			)))]
+			Program::Fd(_) => return ForkError::Exec(Errno::ENOSYS),
+		};
+		ForkError::Exec(Errno::last())
+	}
+}
+
+/// Close `fds`, ignoring errors.
+#[cfg(unix)]
+fn close_fds_(fds: &[Fd]) {
+	for &fd in fds {
+		let _ = unistd::close(fd);
+	}
+}
+
+/// Convert `s` to a `CString`, setting `nul` if it contains a nul byte.
+#[cfg(unix)]
+fn cstring(s: &OsStr, nul: &mut bool) -> CString {
+	CString::new(s.as_bytes()).unwrap_or_else(|_| {
+		*nul = true;
+		CString::default()
+	})
+}
+
+/// Extensions to [`std::process::Command`], giving some of the guarantees of [`fork`] and [`Spawn`] to existing code.
+///
+/// The options are implemented with `pre_exec`, so they run in the child after std's own setup like redirecting stdio, and, as with `pre_exec`, can't be combined with std spawning via `posix_spawn` or `vfork`.
+///
+/// # Example
+/// ```no_run
+/// use palaver::process::*;
+/// use std::process::Command;
+///
+/// let child = Command::new("sleep")
+///     .arg("10")
+///     .new_process_group()
+///     .kill_on_parent_death()
+///     .spawn_handle()
+///     .unwrap();
+/// child.signal(Signal::SIGTERM).unwrap();
+/// ```
+#[cfg(unix)]
+pub trait CommandExt {
+	/// Have the child `SIGKILL`ed on our death, with `PR_SET_PDEATHSIG`. As with [`fork`], the signal is tied to the thread that spawns the child rather than our process, so this should be used from the main thread. Linux and Android only.
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	fn kill_on_parent_death(&mut self) -> &mut Self;
+	/// Move the child into a new process group of its own, with `setpgid`.
+	fn new_process_group(&mut self) -> &mut Self;
+	/// Have each of our fds `from` be `to` in the child, without `FD_CLOEXEC`, as with [`Spawn::fd`]. Spawning fails with `EBADF` if any `from` isn't open.
+	///
+	/// Any `to` that isn't open here is held open on `/dev/null` till the `Command` is dropped, so std's pipe for reporting exec failures can't be created on it.
+	fn inherit_fds(&mut self, fds: &[(Fd, Fd)]) -> &mut Self;
+	/// Spawn the child, returning a [`ChildHandle`] rather than a [`std::process::Child`]. It signals the child with a pidfd, so the pid can't have been reused, and kills the child on drop. Fails with `EINVAL`, having killed the child, if any of its stdio were set to [`Stdio::piped`](std::process::Stdio::piped), as the pipes would be lost; pass pipes of your own instead. Linux 5.3+ only; fails with `ENOSYS` if pidfds aren't available.
+	#[cfg(target_os = "linux")]
+	fn spawn_handle(&mut self) -> io::Result<ChildHandle>;
+}
+#[cfg(unix)]
+impl CommandExt for Command {
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	fn kill_on_parent_death(&mut self) -> &mut Self {
+		let parent = unistd::getpid();
+		unsafe {
+			self.pre_exec(move || {
+				let ret = libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+				if ret != 0 {
+					return Err(io::Error::last_os_error());
+				}
+				// if our parent has already died we've been reparented, and it's too late for the signal
+				if unistd::getppid() != parent {
+					return Err(io::Error::from_raw_os_error(libc::ESRCH));
+				}
+				Ok(())
+			})
+		}
+	}
+	fn new_process_group(&mut self) -> &mut Self {
+		unsafe {
+			self.pre_exec(|| {
+				unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))
+					.map_err(|err| io::Error::from_raw_os_error(errno(err) as i32))
+			})
+		}
+	}
+	fn inherit_fds(&mut self, fds: &[(Fd, Fd)]) -> &mut Self {
+		let mut fds = fds.to_vec();
+		let reserved = ReservedFds::new(fds.iter().map(|&(_, to)| to));
+		// duplicated above all the targets first, so moving them into place can't clobber them
+		let min = fds.iter().map(|&(_, to)| to + 1).max().unwrap_or(0);
+		unsafe {
+			self.pre_exec(move || {
+				let _ = &reserved;
+				for (from, _) in &mut fds {
+					*from = fcntl::fcntl(*from, fcntl::FcntlArg::F_DUPFD_CLOEXEC(min))
+						.map_err(|err| io::Error::from_raw_os_error(errno(err) as i32))?;
+				}
+				file::move_fds(&mut fds, Some(fcntl::FdFlag::empty()), true);
+				Ok(())
+			})
+		}
+	}
+	#[cfg(target_os = "linux")]
+	fn spawn_handle(&mut self) -> io::Result<ChildHandle> {
+		if !pidfd::is_supported() {
+			return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+		}
+		// so reapers of other children leave the child be till it's tracked
+		children::fork_start();
+		let ret = self.spawn().and_then(|mut child| {
+			let pid = Pid::from_raw(child.id().try_into().unwrap());
+			if child.stdin.is_some() || child.stdout.is_some() || child.stderr.is_some() {
+				let _ = child.kill();
+				let _ = child.wait();
+				return Err(io::Error::from_raw_os_error(libc::EINVAL));
+			}
+			// std doesn't reap it on drop, and it's yet to be reaped, so its pid can't have been reused
+			drop(child);
+			let pidfd = pidfd::open(pid).map_err(|err| {
+				let _ = signal::kill(pid, signal::SIGKILL);
+				let _ = ChildHandle::wait_(pid);
+				io::Error::from_raw_os_error(errno(err) as i32)
+			})?;
+			Ok(ChildHandle {
+				pid,
+				owns: Some(Handle {
+					state: AtomicU8::new(0),
+					retainer_killed: AtomicBool::new(false),
+					guard_write: None,
+					pidfd: Some(pidfd),
+					exit_pipe: Mutex::new(None),
+				}),
+				tracked: children::insert(pid),
+				status: Mutex::new(None),
+				received: false,
+			})
+		});
+		children::fork_end();
 		ret
-	} else {
-		if cfg!(target_os = "freebsd") {
-			return basic_fork(false);
+	}
+}
+
+/// Fds held open on `/dev/null` at numbers that weren't in use, closed on drop. [`CommandExt::inherit_fds`] holds its targets, so that std's exec error pipe, created in the parent at the lowest free fds, can't be created on one and then replaced in the child.
+#[cfg(unix)]
+struct ReservedFds(Vec<Fd>);
+#[cfg(unix)]
+impl ReservedFds {
+	fn new(fds: impl Iterator<Item = Fd>) -> Self {
+		let mut reserved = Vec::new();
+		let null = match fcntl::open(
+			"/dev/null",
+			fcntl::OFlag::O_RDONLY | fcntl::OFlag::O_CLOEXEC,
+			stat::Mode::empty(),
+		) {
+			Ok(null) => null,
+			Err(_) => return Self(reserved),
+		};
+		let mut null_reserved = false;
+		for fd in fds {
+			if fd == null {
+				null_reserved = true;
+				continue;
+			}
+			// unlike dup2, this can't replace an fd another thread has just opened
+			match fcntl::fcntl(null, fcntl::FcntlArg::F_DUPFD_CLOEXEC(fd)) {
+				Ok(new) if new == fd => reserved.push(new),
+				Ok(new) => {
+					let _ = unistd::close(new);
+				}
+				Err(_) => (),
+			}
+		}
+		if null_reserved {
+			reserved.push(null);
+		} else {
+			let _ = unistd::close(null);
+		}
+		Self(reserved)
+	}
+}
+#[cfg(unix)]
+impl Drop for ReservedFds {
+	fn drop(&mut self) {
+		for &fd in &self.0 {
+			let _ = unistd::close(fd);
+		}
+	}
+}
+
+/// In the parent: receive the guard fd from the child, or the [`ForkError`] it failed with.
+#[cfg(unix)]
+fn receive_guard(ready_read: &UnixDatagram) -> Result<Fd, ForkError> {
+	let mut msg = [0; 5];
+	match send_fd::receive(ready_read, &mut msg) {
+		Ok(Ok(fd)) => Ok(fd),
+		Ok(Err(5)) => Err(ForkError::from_bytes(msg)),
+		Ok(Err(_)) => Err(ForkError::Handoff(Errno::EBADMSG)),
+		Err(err) => Err(ForkError::Handoff(errno(err))),
+	}
+}
+
+/// In the parent: receive a `len`-byte message from the child into `msg`, or the [`ForkError`] it failed with.
+#[cfg(target_os = "linux")]
+fn receive_msg(ready_read: &UnixDatagram, msg: &mut [u8; 5], len: usize) -> Result<(), ForkError> {
+	match send_fd::receive(ready_read, msg) {
+		Ok(Err(n)) if n == len => Ok(()),
+		Ok(Err(5)) => Err(ForkError::from_bytes(*msg)),
+		Ok(Ok(fd)) => {
+			let _ = unistd::close(fd);
+			Err(ForkError::Handoff(Errno::EBADMSG))
 		}
-		let (ready_read, ready_write) = UnixDatagram::pair().unwrap();
-		Ok(match basic_fork(false)? {
+		Ok(Err(_)) => Err(ForkError::Handoff(Errno::EBADMSG)),
+		Err(err) => Err(ForkError::Handoff(errno(err))),
+	}
+}
+
+/// On Linux when pidfds are available and we're the main thread: have the child SIGKILLed on our death with `PR_SET_PDEATHSIG`, rather than by a watchdog process. The child is killed on drop of its [`ChildHandle`] regardless, and PID reuse is guarded against by the pidfd as with the watchdog.
+#[cfg(target_os = "linux")]
+fn fork_pdeathsig() -> Result<ForkResult, ForkError> {
+	let parent = unistd::getpid();
+	Ok(
+		match basic_fork(false).map_err(|err| ForkError::Fork(errno(err)))? {
 			ForkResult::Child => {
-				drop(ready_read);
-				let new = signal::SigAction::new(
-					signal::SigHandler::SigDfl,
-					signal::SaFlags::empty(),
-					signal::SigSet::empty(),
-				);
-				let old = unsafe { signal::sigaction(signal::SIGCHLD, &new).unwrap() };
-				let pid = unistd::getpid();
-				let group = unistd::getpgrp();
-				let our_group_retainer = if group != pid {
-					let (temp_read, temp_write) = file::pipe(fcntl::OFlag::O_CLOEXEC).unwrap();
-					let child = if let ForkResult::Parent(child) = basic_fork(false)? {
-						child
-					} else {
-						drop(ready_write);
-						unistd::close(temp_write).unwrap();
-						let err = unistd::read(temp_read, &mut [0]).unwrap();
-						assert_eq!(err, 0);
-						signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
-						loop {}
-					};
-					unistd::close(temp_read).unwrap();
-					unistd::setpgid(unistd::Pid::from_raw(0), unistd::Pid::from_raw(0)).unwrap();
-					Some((child, temp_write))
-				} else {
-					None
-				};
-				let (guard_read, guard_write) = file::pipe(fcntl::OFlag::O_CLOEXEC).unwrap();
-				let mut prev = signal::SigSet::empty();
-				signal::sigprocmask(
-					signal::SigmaskHow::SIG_BLOCK,
-					Some(&signal::SigSet::all()),
-					Some(&mut prev),
-				)
-				.unwrap();
-				let our_pid_retainer = if let ForkResult::Parent(child) = basic_fork(false)? {
-					child
-				} else {
-					ignore_signals();
-					signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None)
-						.unwrap();
-					drop(ready_write);
-					if let Some((_retainer, temp_write)) = &our_group_retainer {
-						unistd::close(*temp_write).unwrap();
-					}
-					unistd::close(guard_write).unwrap();
-					for fd in 0..1024 {
-						// TODO // && fd > 2 {
-						if fd != guard_read {
-							let _ = unistd::close(fd);
-						}
-					}
-					let err = unistd::read(guard_read, &mut [0]).unwrap();
-					assert_eq!(err, 0);
-					assert_eq!(unistd::getpgrp(), pid);
-					let _ = signal::kill(pid, signal::SIGKILL);
-					signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
-					loop {}
-				};
-				signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None).unwrap();
-				unistd::close(guard_read).unwrap();
-				send_fd::send_fd(guard_write, &ready_write).unwrap_or_else(|_| {
-					if let Some((retainer, temp_write)) = &our_group_retainer {
-						unistd::close(*temp_write).unwrap();
-						let _ = signal::kill(retainer.pid, signal::SIGKILL);
-					}
-					let _ = signal::kill(our_pid_retainer.pid, signal::SIGKILL);
+				let ret = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+				// if our parent has already died we've been reparented, and it's too late for the signal
+				if ret != 0 || unistd::getppid() != parent {
 					signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
 					loop {}
-				});
-				drop(ready_write);
-				unistd::close(guard_write).unwrap();
-				if let Some((retainer, temp_write)) = our_group_retainer {
-					unistd::getpgid(Some(retainer.pid))
-						.and_then(|group| unistd::setpgid(unistd::Pid::from_raw(0), group))
-						.and_then(|_| signal::kill(retainer.pid, None))
-						.unwrap_or_else(|_| {
-							unistd::close(temp_write).unwrap();
-							let _ = signal::kill(retainer.pid, signal::SIGKILL);
-							let _ = signal::kill(our_pid_retainer.pid, signal::SIGKILL);
-							signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
-							loop {}
-						});
-					let _ = signal::kill(retainer.pid, signal::SIGKILL);
-					let _ = retainer.wait().unwrap();
-				}
-				if new.handler() != old.handler() {
-					let new2 = unsafe { signal::sigaction(signal::SIGCHLD, &old).unwrap() };
-					assert_eq!(new.handler(), new2.handler());
 				}
 				ForkResult::Child
 			}
 			ForkResult::Parent(mut child) => {
-				drop(ready_write);
-				let guard_write = send_fd::receive_fd(&ready_read).unwrap_or_else(|_| {
-					signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
-					loop {}
-				});
-				drop(ready_read);
+				// the child is yet to be reaped, so its pid can't have been reused
+				let pidfd = match pidfd::open(child.pid) {
+					Ok(pidfd) => pidfd,
+					Err(err) => {
+						let _ = signal::kill(child.pid, signal::SIGKILL);
+						let _ = ChildHandle::wait_(child.pid);
+						child.set_reaped();
+						return Err(ForkError::Pidfd(errno(err)));
+					}
+				};
 				child.owns = Some(Handle {
 					state: AtomicU8::new(0),
-					#[cfg(not(target_os = "freebsd"))]
-					guard_write,
+					retainer_killed: AtomicBool::new(false),
+					guard_write: None,
+					pidfd: Some(pidfd),
+					exit_pipe: Mutex::new(None),
 				});
-				let _ = guard_write;
 				ForkResult::Parent(child)
 			}
-		})
+		},
+	)
+}
+
+/// In the child where pidfds aren't available: make our pid and group unreusable while the parent's [`ChildHandle`] is alive, with a pid retainer process in our group that SIGKILLs us once `guard_write` is closed, i.e. once the parent's [`ChildHandle`] is dropped or the parent dies. We move into our own group for this, so there may also be a temporary group retainer keeping our original group alive till we're back in it.
+#[cfg(unix)]
+#[allow(clippy::too_many_lines)]
+fn fork_retainers(ready_write: &UnixDatagram) -> Result<(), ForkError> {
+	let new = signal::SigAction::new(
+		signal::SigHandler::SigDfl,
+		signal::SaFlags::empty(),
+		signal::SigSet::empty(),
+	);
+	let old = unsafe { signal::sigaction(signal::SIGCHLD, &new) }
+		.map_err(|err| ForkError::Signal(errno(err)))?;
+	let pid = unistd::getpid();
+	let group = unistd::getpgrp();
+	let our_group_retainer = if group != pid {
+		let (temp_read, temp_write) =
+			file::pipe(fcntl::OFlag::O_CLOEXEC).map_err(|err| ForkError::Pipe(errno(err)))?;
+		let child = match basic_fork(false) {
+			Ok(ForkResult::Parent(child)) => child,
+			Ok(ForkResult::Child) => {
+				let _ = unistd::close(ready_write.as_raw_fd());
+				let _ = unistd::close(temp_write);
+				let err = unistd::read(temp_read, &mut [0]).unwrap();
+				assert_eq!(err, 0);
+				signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
+				loop {}
+			}
+			Err(err) => {
+				let _ = unistd::close(temp_read);
+				let _ = unistd::close(temp_write);
+				return Err(ForkError::Fork(errno(err)));
+			}
+		};
+		let _ = unistd::close(temp_read);
+		if let Err(err) = unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0)) {
+			let _ = unistd::close(temp_write);
+			let _ = signal::kill(child.pid, signal::SIGKILL);
+			return Err(ForkError::ProcessGroup(errno(err)));
+		}
+		Some((child, temp_write))
+	} else {
+		None
+	};
+	let kill_group_retainer = |our_group_retainer: &Option<(ChildHandle, Fd)>| {
+		if let Some((retainer, temp_write)) = our_group_retainer {
+			let _ = unistd::close(*temp_write);
+			let _ = signal::kill(retainer.pid, signal::SIGKILL);
+		}
+	};
+	let (guard_read, guard_write) = file::pipe(fcntl::OFlag::O_CLOEXEC).map_err(|err| {
+		kill_group_retainer(&our_group_retainer);
+		ForkError::Pipe(errno(err))
+	})?;
+	let mut prev = signal::SigSet::empty();
+	if let Err(err) = signal::sigprocmask(
+		signal::SigmaskHow::SIG_BLOCK,
+		Some(&signal::SigSet::all()),
+		Some(&mut prev),
+	) {
+		kill_group_retainer(&our_group_retainer);
+		return Err(ForkError::Signal(errno(err)));
+	}
+	let our_pid_retainer = match basic_fork(false) {
+		Ok(ForkResult::Parent(child)) => child,
+		Ok(ForkResult::Child) => {
+			ignore_signals();
+			let _ = signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None);
+			let _ = unistd::close(ready_write.as_raw_fd());
+			if let Some((_retainer, temp_write)) = &our_group_retainer {
+				let _ = unistd::close(*temp_write);
+			}
+			let _ = unistd::close(guard_write);
+			for fd in 0..1024 {
+				// TODO // && fd > 2 {
+				if fd != guard_read {
+					let _ = unistd::close(fd);
+				}
+			}
+			let err = unistd::read(guard_read, &mut [0]).unwrap();
+			assert_eq!(err, 0);
+			assert_eq!(unistd::getpgrp(), pid);
+			let _ = signal::kill(pid, signal::SIGKILL);
+			signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
+			loop {}
+		}
+		Err(err) => {
+			let _ = signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None);
+			let _ = unistd::close(guard_read);
+			let _ = unistd::close(guard_write);
+			kill_group_retainer(&our_group_retainer);
+			return Err(ForkError::Fork(errno(err)));
+		}
+	};
+	let _ = unistd::close(guard_read);
+	let fail = |err| {
+		let _ = unistd::close(guard_write);
+		kill_group_retainer(&our_group_retainer);
+		let _ = signal::kill(our_pid_retainer.pid, signal::SIGKILL);
+		err
+	};
+	signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None)
+		.map_err(|err| fail(ForkError::Signal(errno(err))))?;
+	if let Some((retainer, temp_write)) = &our_group_retainer {
+		// back into our original group, which the group retainer has kept alive, before the parent hears we're ready and might look up our group
+		unistd::getpgid(Some(retainer.pid))
+			.and_then(|group| unistd::setpgid(Pid::from_raw(0), group))
+			.and_then(|()| signal::kill(retainer.pid, None))
+			.map_err(|err| fail(ForkError::ProcessGroup(errno(err))))?;
+		let _ = unistd::close(*temp_write);
+		let _ = signal::kill(retainer.pid, signal::SIGKILL);
+	}
+	send_fd::send_fd(guard_write, ready_write)
+		.map_err(|err| fail(ForkError::Handoff(errno(err))))?;
+	let _ = unistd::close(guard_write);
+	if let Some((retainer, _temp_write)) = our_group_retainer {
+		let _ = retainer.wait();
+	}
+	if new.handler() != old.handler() {
+		let new2 = unsafe { signal::sigaction(signal::SIGCHLD, &old) }.map_err(|err| {
+			let _ = signal::kill(our_pid_retainer.pid, signal::SIGKILL);
+			ForkError::Signal(errno(err))
+		})?;
+		assert_eq!(new.handler(), new2.handler());
+	}
+	Ok(())
+}
+
+/// In the child on Linux when pidfds are available: fork a watchdog that SIGKILLs us via a pidfd once `guard_write` is closed, i.e. once the parent's [`ChildHandle`] is dropped or the parent dies. PID reuse is guarded against by the pidfd the parent holds, so the pid and group retainers used elsewhere aren't needed.
+///
+/// If `sibling` is given, the watchdog SIGKILLs the process it's a pidfd for instead of us. [`fork_with`] uses this for a child in a new pid namespace, as that namespace's init can't be SIGKILLed from inside it.
+#[cfg(target_os = "linux")]
+fn fork_watchdog(ready_write: &UnixDatagram, sibling: Option<Fd>) -> Result<(), ForkError> {
+	let pid = unistd::getpid();
+	let (guard_read, guard_write) =
+		file::pipe(fcntl::OFlag::O_CLOEXEC).map_err(|err| ForkError::Pipe(errno(err)))?;
+	let mut prev = signal::SigSet::empty();
+	if let Err(err) = signal::sigprocmask(
+		signal::SigmaskHow::SIG_BLOCK,
+		Some(&signal::SigSet::all()),
+		Some(&mut prev),
+	) {
+		let _ = unistd::close(guard_read);
+		let _ = unistd::close(guard_write);
+		return Err(ForkError::Signal(errno(err)));
 	}
+	let watchdog = match basic_fork(false) {
+		Ok(ForkResult::Parent(child)) => child,
+		Ok(ForkResult::Child) => {
+			ignore_signals();
+			let _ = signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None);
+			let _ = unistd::close(ready_write.as_raw_fd());
+			let _ = unistd::close(guard_write);
+			// if our parent has already died we've been reparented and there's nothing to watch
+			let watched =
+				sibling.or_else(|| pidfd::open(pid).ok().filter(|_| unistd::getppid() == pid));
+			if let Some(watched) = watched {
+				for fd in 0..1024 {
+					if fd != guard_read && fd != watched {
+						let _ = unistd::close(fd);
+					}
+				}
+				let err = unistd::read(guard_read, &mut [0]).unwrap();
+				assert_eq!(err, 0);
+				let _ = pidfd::send_signal(watched, Some(signal::SIGKILL));
+			}
+			signal::kill(unistd::getpid(), signal::SIGKILL).unwrap();
+			loop {}
+		}
+		Err(err) => {
+			let _ = signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None);
+			let _ = unistd::close(guard_read);
+			let _ = unistd::close(guard_write);
+			return Err(ForkError::Fork(errno(err)));
+		}
+	};
+	let _ = unistd::close(guard_read);
+	let fail = |err| {
+		let _ = unistd::close(guard_write);
+		let _ = signal::kill(watchdog.pid, signal::SIGKILL);
+		err
+	};
+	signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&prev), None)
+		.map_err(|err| fail(ForkError::Signal(errno(err))))?;
+	send_fd::send_fd(guard_write, ready_write)
+		.map_err(|err| fail(ForkError::Handoff(errno(err))))?;
+	let _ = unistd::close(guard_write);
+	Ok(())
+}
+
+/// Register handlers to run around forks, for example to take a library's locks before forking and release or reinitialise them after, so the child isn't left with locks held by threads that don't exist in it. `prepare` runs in the parent before forking, `parent` in the parent after, and `child` in the child. As with `pthread_atfork`, `prepare` handlers run in the reverse order of registration and the others in order.
+///
+/// They run once for each call to [`fork`], [`fork_with`] and [`daemonize`], however many helper processes it forks: `prepare` before the first, `parent` once the call's about to return, whether or not it succeeded, and `child` only in the process that returns [`ForkResult::Child`]. They're also registered with `pthread_atfork`, so run around other calls to `fork(2)`, as by `nix::unistd::fork`.
+///
+/// Handlers can't be unregistered. `child` runs in a copy of what might be a multithreaded process, in which only the forking thread exists, so it should stick to resetting state rather than anything that might wait on another thread.
+///
+/// # Example
+/// ```
+/// use palaver::process::atfork;
+/// use std::sync::atomic::{AtomicI32, Ordering};
+///
+/// // a cached pid, which would be stale in the child
+/// static PID: AtomicI32 = AtomicI32::new(0);
+///
+/// atfork(|| (), || (), || PID.store(0, Ordering::Relaxed)).unwrap();
+/// ```
+#[cfg(unix)]
+pub fn atfork<P, A, C>(prepare: P, parent: A, child: C) -> nix::Result<()>
+where
+	P: Fn() + Send + Sync + 'static,
+	A: Fn() + Send + Sync + 'static,
+	C: Fn() + Send + Sync + 'static,
+{
+	handlers::register(Box::new(prepare), Box::new(parent), Box::new(child))
 }
 
 #[cfg(unix)]
 fn basic_fork(may_outlive: bool) -> nix::Result<ForkResult> {
+	children::fork_start();
+	let ret = basic_fork_(may_outlive);
+	children::fork_end();
+	ret
+}
+#[cfg(unix)]
+fn basic_fork_(may_outlive: bool) -> nix::Result<ForkResult> {
 	#[cfg(target_os = "freebsd")]
 	{
 		let mut pd = -1;
@@ -388,11 +3655,18 @@ fn basic_fork(may_outlive: bool) -> nix::Result<ForkResult> {
 		};
 		Errno::result(res).map(|res| match res {
 			0 => ForkResult::Child,
-			pid => ForkResult::Parent(ChildHandle {
-				pid: Pid::from_raw(pid),
-				pd,
-				owns: None,
-			}),
+			pid => {
+				let pid = Pid::from_raw(pid);
+				let tracked = children::insert(pid);
+				ForkResult::Parent(ChildHandle {
+					pid,
+					pd,
+					owns: None,
+					tracked,
+					status: Mutex::new(None),
+					received: false,
+				})
+			}
 		})
 	}
 	#[cfg(not(target_os = "freebsd"))]
@@ -403,6 +3677,9 @@ fn basic_fork(may_outlive: bool) -> nix::Result<ForkResult> {
 			unistd::ForkResult::Parent { child } => ForkResult::Parent(ChildHandle {
 				pid: child,
 				owns: None,
+				tracked: children::insert(child),
+				status: Mutex::new(None),
+				received: false,
 			}),
 		})
 	}
@@ -423,80 +3700,335 @@ fn ignore_signals() {
 	}
 }
 
+/// The handlers registered with [`atfork`], in a list that's appended to under a lock but read without one, so they can be run around `fork` without allocating.
 #[cfg(unix)]
-mod send_fd {
-	#![allow(trivial_casts)]
+mod handlers {
+	use nix::{errno::Errno, libc, Error};
+	use std::{
+		cell::Cell, ptr, sync::{
+			atomic::{AtomicPtr, Ordering}, Mutex
+		}
+	};
 
-	use nix::errno::Errno;
+	pub type Handler = Box<dyn Fn() + Send + Sync>;
+
+	struct Handlers {
+		prepare: Handler,
+		parent: Handler,
+		child: Handler,
+		next: *mut Handlers,
+	}
+
+	/// The most recently registered first.
+	static HEAD: AtomicPtr<Handlers> = AtomicPtr::new(ptr::null_mut());
+	lazy_static::lazy_static! {
+		/// Held while registering, and set once we've registered with `pthread_atfork`.
+		static ref REGISTERED: Mutex<bool> = Mutex::new(false);
+	}
+
+	thread_local! {
+		/// Set while this thread is in one of our forks, so the `pthread_atfork` handlers leave it to [`prepare`], [`parent`] and [`child`]. Inherited by the child.
+		static FORKING: Cell<bool> = Cell::new(false);
+	}
+
+	pub fn register(prepare: Handler, parent: Handler, child: Handler) -> nix::Result<()> {
+		let mut registered = REGISTERED.lock().unwrap();
+		if !*registered {
+			let ret = unsafe {
+				libc::pthread_atfork(
+					Some(atfork_prepare),
+					Some(atfork_parent),
+					Some(atfork_child),
+				)
+			};
+			if ret != 0 {
+				return Err(Error::Sys(Errno::from_i32(ret)));
+			}
+			*registered = true;
+		}
+		let handlers = Box::new(Handlers {
+			prepare,
+			parent,
+			child,
+			next: HEAD.load(Ordering::Acquire),
+		});
+		HEAD.store(Box::into_raw(handlers), Ordering::Release);
+		Ok(())
+	}
+
+	/// Before one of our forks, in the forking thread.
+	pub fn prepare() {
+		FORKING.with(|forking| forking.set(true));
+		run_prepare();
+	}
+	/// After one of our forks, in the parent, whether or not it succeeded.
+	pub fn parent() {
+		run_in_order(HEAD.load(Ordering::Acquire), |handlers| &handlers.parent);
+		FORKING.with(|forking| forking.set(false));
+	}
+	/// After one of our forks, in the child that's returned to the caller.
+	pub fn child() {
+		run_in_order(HEAD.load(Ordering::Acquire), |handlers| &handlers.child);
+		FORKING.with(|forking| forking.set(false));
+	}
+
+	fn forking() -> bool {
+		FORKING.try_with(Cell::get).unwrap_or(false)
+	}
+	extern "C" fn atfork_prepare() {
+		if !forking() {
+			run_prepare();
+		}
+	}
+	extern "C" fn atfork_parent() {
+		if !forking() {
+			run_in_order(HEAD.load(Ordering::Acquire), |handlers| &handlers.parent);
+		}
+	}
+	extern "C" fn atfork_child() {
+		if !forking() {
+			run_in_order(HEAD.load(Ordering::Acquire), |handlers| &handlers.child);
+		}
+	}
+
+	/// In the reverse order of registration, as with `pthread_atfork`.
+	fn run_prepare() {
+		let mut handlers = HEAD.load(Ordering::Acquire);
+		while let Some(handlers_) = unsafe { handlers.as_ref() } {
+			(handlers_.prepare)();
+			handlers = handlers_.next;
+		}
+	}
+	/// In the order of registration, as with `pthread_atfork`.
+	fn run_in_order(handlers: *mut Handlers, handler: fn(&Handlers) -> &Handler) {
+		if let Some(handlers) = unsafe { handlers.as_ref() } {
+			run_in_order(handlers.next, handler);
+			handler(handlers)();
+		}
+	}
+}
+
+/// The children we've forked and are yet to reap, so that reapers of other children, like [`Subreaper`], leave them be. This is lock- and allocation-free so it can be used around `fork`.
+#[cfg(unix)]
+mod children {
+	use nix::unistd::Pid;
+	use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+	const CAPACITY: usize = 1024;
+	#[allow(clippy::declare_interior_mutable_const)]
+	const EMPTY: AtomicI32 = AtomicI32::new(0);
+	static PIDS: [AtomicI32; CAPACITY] = [EMPTY; CAPACITY];
+	/// Children that didn't fit in `PIDS`. While there are any, all our children are assumed to be ours.
+	static UNTRACKED: AtomicUsize = AtomicUsize::new(0);
+	/// Forks in progress, i.e. children that might exist but are yet to be inserted.
+	static FORKING: AtomicUsize = AtomicUsize::new(0);
+
+	/// Where a child is tracked: 0 = nowhere, `usize::MAX` = `UNTRACKED`, `i` = `PIDS[i - 1]`.
+	#[derive(Default, Debug)]
+	pub struct Tracked(AtomicUsize);
+
+	pub fn fork_start() {
+		let _ = FORKING.fetch_add(1, Ordering::SeqCst);
+	}
+	/// Called in both the parent, after inserting the child, and the child.
+	pub fn fork_end() {
+		if FORKING.fetch_sub(1, Ordering::SeqCst) == 1 {
+			super::wake_subreaper();
+		}
+	}
+	pub fn insert(pid: Pid) -> Tracked {
+		for (i, slot) in PIDS.iter().enumerate() {
+			if slot
+				.compare_exchange(0, pid.as_raw(), Ordering::SeqCst, Ordering::SeqCst)
+				.is_ok()
+			{
+				return Tracked(AtomicUsize::new(i + 1));
+			}
+		}
+		let _ = UNTRACKED.fetch_add(1, Ordering::SeqCst);
+		Tracked(AtomicUsize::new(usize::MAX))
+	}
+	/// Idempotent, so can be called on reaping and again on drop.
+	pub fn remove(tracked: &Tracked) {
+		match tracked.0.swap(0, Ordering::SeqCst) {
+			0 => (),
+			usize::MAX => {
+				if UNTRACKED.fetch_sub(1, Ordering::SeqCst) == 1 {
+					super::wake_subreaper();
+				}
+			}
+			i => PIDS[i - 1].store(0, Ordering::SeqCst),
+		}
+	}
+	/// Whether `pid`, which must be a child of ours, is one we haven't forked ourselves.
+	pub fn is_foreign(pid: Pid) -> bool {
+		FORKING.load(Ordering::SeqCst) == 0
+			&& UNTRACKED.load(Ordering::SeqCst) == 0
+			&& PIDS
+				.iter()
+				.all(|slot| slot.load(Ordering::SeqCst) != pid.as_raw())
+	}
+}
+
+/// [pidfd](http://man7.org/linux/man-pages/man2/pidfd_open.2.html) syscalls, which nix and libc don't yet wrap.
+#[cfg(target_os = "linux")]
+mod pidfd {
+	use nix::{
+		errno::Errno, libc, sys::signal::Signal, unistd::{self, Pid}, Error
+	};
 	use std::{
-		convert::TryInto, mem, os::unix::{
-			io::{AsRawFd, RawFd}, net::UnixDatagram
-		}, ptr::{read_unaligned, write_unaligned}
+		convert::TryInto, mem::{self, size_of}, ptr, sync::atomic::{AtomicU8, Ordering}
 	};
 
-	const BUF_SIZE: usize = 32; // mac is 16, linux 20, should be big enough everywhere?
+	use crate::Fd;
 
-	// https://github.com/Aaron1011/spawn-pidfd/blob/44c40733905b0d793ac8393079ecb393774cedda/src/lib.rs#L63-L123
-	pub fn send_fd(fd: RawFd, sock: &UnixDatagram) -> nix::Result<()> {
-		let mut msg: libc::msghdr = unsafe { mem::zeroed() };
-		let fds: [libc::c_int; 1] = [fd];
-		let buf_size = unsafe {
-			libc::CMSG_SPACE(std::mem::size_of::<[libc::c_int; 1]>().try_into().unwrap()) as usize
+	/// Whether `pidfd_open` (Linux 5.3+) and thus `pidfd_send_signal` (Linux 5.1+) are available. The result is cached unless the check failed for a transient reason like running out of fds.
+	pub fn is_supported() -> bool {
+		static SUPPORTED: AtomicU8 = AtomicU8::new(0); // 0 = unknown, 1 = supported, 2 = unsupported
+		match SUPPORTED.load(Ordering::Relaxed) {
+			1 => true,
+			2 => false,
+			_ => match open(unistd::getpid()) {
+				Ok(fd) => {
+					unistd::close(fd).unwrap();
+					SUPPORTED.store(1, Ordering::Relaxed);
+					true
+				}
+				Err(Error::Sys(Errno::EMFILE))
+				| Err(Error::Sys(Errno::ENFILE))
+				| Err(Error::Sys(Errno::ENOMEM)) => false,
+				Err(_) => {
+					SUPPORTED.store(2, Ordering::Relaxed);
+					false
+				}
+			},
+		}
+	}
+
+	/// `pidfd_open`. The returned fd is CLOEXEC.
+	pub fn open(pid: Pid) -> nix::Result<Fd> {
+		let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+		Errno::result(ret).map(|fd| fd.try_into().unwrap())
+	}
+
+	/// `clone3` with `CLONE_PARENT | CLONE_PIDFD` and `flags` (Linux 5.3+): fork a sibling, i.e. a new child of our parent, returning its pid and a pidfd for it, or `None` in the sibling. This bypasses libc's `fork` handling, so must only be called from a single-threaded process.
+	///
+	/// libc caches the calling thread's TID, and a raw `clone3` leaves the sibling with ours, so in the sibling `raise`, `abort` and `pthread_kill` can signal the wrong thread (on musl, ours). The sibling should `exec` or `_exit` without calling them.
+	pub fn fork_sibling(flags: libc::c_int) -> nix::Result<Option<(Pid, Fd)>> {
+		// `struct clone_args` as of Linux 5.3, which libc doesn't expose
+		#[repr(C)]
+		struct CloneArgs {
+			flags: u64,
+			pidfd: u64,
+			child_tid: u64,
+			parent_tid: u64,
+			exit_signal: u64, // must be 0 with CLONE_PARENT, which takes our exit signal
+			stack: u64,
+			stack_size: u64,
+			tls: u64,
+		}
+		let mut pidfd = mem::MaybeUninit::<libc::c_int>::uninit();
+		let args = CloneArgs {
+			flags: (libc::CLONE_PARENT | libc::CLONE_PIDFD | flags)
+				.try_into()
+				.unwrap(),
+			pidfd: pidfd.as_mut_ptr() as u64,
+			child_tid: 0,
+			parent_tid: 0,
+			exit_signal: 0,
+			stack: 0,
+			stack_size: 0,
+			tls: 0,
 		};
-		assert!(BUF_SIZE >= buf_size, "{} < {}", BUF_SIZE, buf_size);
-		let mut buf: [libc::c_char; BUF_SIZE] = unsafe { mem::zeroed() };
+		let ret = unsafe { libc::syscall(libc::SYS_clone3, &args, size_of::<CloneArgs>()) };
+		Errno::result(ret).map(|pid| match pid {
+			0 => None,
+			pid => Some((Pid::from_raw(pid.try_into().unwrap()), unsafe {
+				pidfd.assume_init()
+			})),
+		})
+	}
 
-		msg.msg_control = buf.as_mut_ptr() as *mut libc::c_void;
-		msg.msg_controllen = mem::size_of_val(&buf).try_into().unwrap();
+	/// `pidfd_send_signal` with a `siginfo_t` like that `sigqueue` would send, carrying `value`.
+	pub fn queue_signal(pidfd: Fd, signal: libc::c_int, value: libc::c_int) -> nix::Result<()> {
+		// the `_rt` member of the union in `siginfo_t`, which libc doesn't expose
+		#[repr(C)]
+		struct Rt {
+			pid: libc::pid_t,
+			uid: libc::uid_t,
+			value: super::SigVal,
+		}
+		#[repr(C)]
+		struct SigInfo {
+			signo: libc::c_int,
+			errno: libc::c_int,
+			code: libc::c_int,
+			rt: Rt,
+		}
+		assert!(size_of::<SigInfo>() <= size_of::<libc::siginfo_t>());
+		let mut info = mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+		unsafe {
+			info.as_mut_ptr().cast::<SigInfo>().write(SigInfo {
+				signo: signal,
+				errno: 0,
+				code: libc::SI_QUEUE,
+				rt: Rt {
+					pid: libc::getpid(),
+					uid: libc::getuid(),
+					value: super::SigVal { int: value },
+				},
+			});
+		}
+		let ret =
+			unsafe { libc::syscall(libc::SYS_pidfd_send_signal, pidfd, signal, info.as_ptr(), 0) };
+		Errno::result(ret).map(drop)
+	}
 
-		let mut iov: [libc::iovec; 1] = unsafe { mem::zeroed() };
-		iov[0].iov_base = &mut () as *mut _ as *mut libc::c_void;
-		iov[0].iov_len = 0;
+	/// `pidfd_send_signal`. `None` checks the process is still alive, like `kill(pid, 0)`.
+	pub fn send_signal(pidfd: Fd, signal: Option<Signal>) -> nix::Result<()> {
+		let signal = match signal {
+			Some(s) => s as libc::c_int,
+			None => 0,
+		};
+		let ret = unsafe {
+			libc::syscall(
+				libc::SYS_pidfd_send_signal,
+				pidfd,
+				signal,
+				ptr::null::<libc::siginfo_t>(),
+				0,
+			)
+		};
+		Errno::result(ret).map(drop)
+	}
+}
 
-		msg.msg_iov = iov.as_mut_ptr();
-		msg.msg_iovlen = 1;
+#[cfg(unix)]
+mod send_fd {
+	#[cfg(target_os = "freebsd")]
+	use nix::errno::Errno;
+	use std::os::unix::{
+		io::{AsRawFd, RawFd}, net::UnixDatagram
+	};
 
-		let cmsg: *mut libc::cmsghdr;
-		unsafe {
-			cmsg = libc::CMSG_FIRSTHDR(&msg);
-			(*cmsg).cmsg_level = libc::SOL_SOCKET;
-			(*cmsg).cmsg_type = libc::SCM_RIGHTS;
-			(*cmsg).cmsg_len =
-				(libc::CMSG_LEN((mem::size_of::<[libc::c_int; 1]>()).try_into().unwrap())
-					as libc::size_t)
-					.try_into()
-					.unwrap();
-			#[allow(clippy::cast_ptr_alignment)]
-			write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, fds[0]);
-			msg.msg_controllen = (*cmsg).cmsg_len;
+	use crate::socket;
 
-			let ret = libc::sendmsg(sock.as_raw_fd(), &msg, 0);
-			Errno::result(ret).map(drop)
-		}
+	pub fn send_fd(fd: RawFd, sock: &UnixDatagram) -> nix::Result<()> {
+		// the fd needs a byte to go with
+		socket::send_fds(sock.as_raw_fd(), &[0], &[fd], false).map(drop)
 	}
+	#[cfg(target_os = "freebsd")]
 	pub fn receive_fd(sock: &UnixDatagram) -> nix::Result<RawFd> {
-		let mut msg: libc::msghdr = unsafe { mem::zeroed() };
-		let buf_size = unsafe {
-			libc::CMSG_SPACE(std::mem::size_of::<[libc::c_int; 1]>().try_into().unwrap()) as usize
-		};
-		assert!(BUF_SIZE >= buf_size, "{} < {}", BUF_SIZE, buf_size);
-		let mut buf: [libc::c_char; BUF_SIZE] = unsafe { mem::zeroed() };
-
-		let mut dummy: libc::c_char = 0;
-		let mut iov: [libc::iovec; 1] = unsafe { mem::zeroed() };
-		iov[0].iov_base = &mut dummy as *mut _ as *mut libc::c_void;
-		iov[0].iov_len = 1;
-
-		msg.msg_iov = iov.as_mut_ptr();
-		msg.msg_iovlen = 1;
-		msg.msg_control = buf.as_mut_ptr() as *mut libc::c_void;
-		msg.msg_controllen = mem::size_of_val(&buf).try_into().unwrap();
-		let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg as *mut libc::msghdr, 0) };
-		Errno::result(ret).map(|_r| {
-			let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
-			#[allow(clippy::cast_ptr_alignment)]
-			let fd = unsafe { read_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int) };
-			fd
+		receive(sock, &mut [0])?.map_err(|_| nix::Error::Sys(Errno::EBADMSG))
+	}
+	/// Receive an fd, or if the message doesn't carry one, its length with the first `data.len()` bytes of it copied to `data`.
+	pub fn receive(sock: &UnixDatagram, data: &mut [u8]) -> nix::Result<Result<RawFd, usize>> {
+		let mut fds = [0; 1];
+		let received = socket::recv_fds(sock.as_raw_fd(), data, &mut fds)?;
+		Ok(if received.fds == 1 {
+			Ok(fds[0])
+		} else {
+			Err(received.len)
 		})
 	}
 }
@@ -518,4 +4050,19 @@ mod tests {
 			); // TODO: retry to avoid bad luck flakiness?
 		}
 	}
+
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	#[test]
+	fn list() {
+		let pid = nix::unistd::getpid();
+		let process = super::list()
+			.unwrap()
+			.find(|process| process.pid == pid)
+			.unwrap();
+		assert_eq!(process.ppid, nix::unistd::getppid());
+		assert_eq!(process.pgid, nix::unistd::getpgrp());
+		assert!(!process.comm.is_empty());
+		assert_ne!(process.threads, 0);
+		assert!(process.start_time <= std::time::SystemTime::now());
+	}
 }
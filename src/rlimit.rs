@@ -0,0 +1,224 @@
+//! Resource limit-related functionality
+
+use nix::{errno::Errno, libc, Error};
+use std::mem;
+
+#[cfg(target_os = "freebsd")]
+use std::convert::TryInto;
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+use libc::{getrlimit, rlimit, setrlimit};
+// 64-bit limits on Linux and Android, even on 32-bit platforms
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use libc::{getrlimit64 as getrlimit, rlimit64 as rlimit, setrlimit64 as setrlimit};
+
+#[cfg(all(target_os = "linux", not(target_env = "musl")))]
+type RawResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", not(target_env = "musl"))))]
+type RawResource = libc::c_int;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+type RawLimit = u64;
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+type RawLimit = libc::rlim_t;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const INFINITY: RawLimit = !0;
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+const INFINITY: RawLimit = libc::RLIM_INFINITY;
+
+/// A resource whose use can be limited. See [getrlimit(2)](http://man7.org/linux/man-pages/man2/getrlimit.2.html) for the details on each platform.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Resource {
+	/// Size of the virtual address space, in bytes
+	RLIMIT_AS,
+	/// Size of core dumps, in bytes
+	RLIMIT_CORE,
+	/// CPU time, in seconds
+	RLIMIT_CPU,
+	/// Size of the data segment, in bytes
+	RLIMIT_DATA,
+	/// Size of files that can be created, in bytes
+	RLIMIT_FSIZE,
+	/// Memory that can be locked into RAM, in bytes
+	RLIMIT_MEMLOCK,
+	/// One more than the highest fd that can be opened
+	RLIMIT_NOFILE,
+	/// Processes, or on Linux threads, for the real user id
+	RLIMIT_NPROC,
+	/// Resident set size, in bytes
+	RLIMIT_RSS,
+	/// Size of the main thread's stack, in bytes
+	RLIMIT_STACK,
+	/// File locks. Linux and Android only.
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	RLIMIT_LOCKS,
+	/// Bytes in POSIX message queues. Linux and Android only.
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	RLIMIT_MSGQUEUE,
+	/// Ceiling of the nice value, as `20 - limit`. Linux and Android only.
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	RLIMIT_NICE,
+	/// Ceiling of the real-time priority. Linux and Android only.
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	RLIMIT_RTPRIO,
+	/// CPU time under real-time scheduling without blocking, in microseconds. Linux only.
+	#[cfg(target_os = "linux")]
+	RLIMIT_RTTIME,
+	/// Queued signals. Linux and Android only.
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	RLIMIT_SIGPENDING,
+	/// Socket buffer size, in bytes. FreeBSD and NetBSD only.
+	#[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+	RLIMIT_SBSIZE,
+	/// Swap space, in bytes. FreeBSD only.
+	#[cfg(target_os = "freebsd")]
+	RLIMIT_SWAP,
+	/// Pseudo-terminals. FreeBSD only.
+	#[cfg(target_os = "freebsd")]
+	RLIMIT_NPTS,
+	/// Kqueues. FreeBSD only.
+	#[cfg(target_os = "freebsd")]
+	RLIMIT_KQUEUES,
+}
+impl Resource {
+	fn as_raw(self) -> RawResource {
+		match self {
+			Self::RLIMIT_AS => libc::RLIMIT_AS,
+			Self::RLIMIT_CORE => libc::RLIMIT_CORE,
+			Self::RLIMIT_CPU => libc::RLIMIT_CPU,
+			Self::RLIMIT_DATA => libc::RLIMIT_DATA,
+			Self::RLIMIT_FSIZE => libc::RLIMIT_FSIZE,
+			Self::RLIMIT_MEMLOCK => libc::RLIMIT_MEMLOCK,
+			Self::RLIMIT_NOFILE => libc::RLIMIT_NOFILE,
+			Self::RLIMIT_NPROC => libc::RLIMIT_NPROC,
+			Self::RLIMIT_RSS => libc::RLIMIT_RSS,
+			Self::RLIMIT_STACK => libc::RLIMIT_STACK,
+			#[cfg(any(target_os = "android", target_os = "linux"))]
+			Self::RLIMIT_LOCKS => libc::RLIMIT_LOCKS,
+			#[cfg(any(target_os = "android", target_os = "linux"))]
+			Self::RLIMIT_MSGQUEUE => libc::RLIMIT_MSGQUEUE,
+			#[cfg(any(target_os = "android", target_os = "linux"))]
+			Self::RLIMIT_NICE => libc::RLIMIT_NICE,
+			#[cfg(any(target_os = "android", target_os = "linux"))]
+			Self::RLIMIT_RTPRIO => libc::RLIMIT_RTPRIO,
+			#[cfg(target_os = "linux")]
+			Self::RLIMIT_RTTIME => libc::RLIMIT_RTTIME,
+			#[cfg(any(target_os = "android", target_os = "linux"))]
+			Self::RLIMIT_SIGPENDING => libc::RLIMIT_SIGPENDING,
+			#[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+			Self::RLIMIT_SBSIZE => libc::RLIMIT_SBSIZE,
+			#[cfg(target_os = "freebsd")]
+			Self::RLIMIT_SWAP => libc::RLIMIT_SWAP,
+			#[cfg(target_os = "freebsd")]
+			Self::RLIMIT_NPTS => libc::RLIMIT_NPTS,
+			#[cfg(target_os = "freebsd")]
+			Self::RLIMIT_KQUEUES => libc::RLIMIT_KQUEUES,
+		}
+	}
+}
+
+/// The soft and hard limits on a resource, with `None` being unlimited, i.e. `RLIM_INFINITY`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Rlimit {
+	/// The limit that's enforced. An unprivileged process can set it up to the hard limit.
+	pub soft: Option<u64>,
+	/// The ceiling for the soft limit. An unprivileged process can only lower it, irreversibly.
+	pub hard: Option<u64>,
+}
+impl Rlimit {
+	fn from_raw(rlim: rlimit) -> Self {
+		let from_raw = |limit: RawLimit| {
+			if limit == INFINITY {
+				None
+			} else {
+				// rlim_t is signed on FreeBSD
+				#[cfg(target_os = "freebsd")]
+				let limit = limit.try_into().unwrap();
+				Some(limit)
+			}
+		};
+		Self {
+			soft: from_raw(rlim.rlim_cur),
+			hard: from_raw(rlim.rlim_max),
+		}
+	}
+	fn to_raw(self) -> nix::Result<rlimit> {
+		let to_raw = |limit: Option<u64>| match limit {
+			None => Ok(INFINITY),
+			Some(limit) => {
+				#[cfg(target_os = "freebsd")]
+				let limit: RawLimit = limit.try_into().map_err(|_| Error::Sys(Errno::EINVAL))?;
+				if limit == INFINITY {
+					Err(Error::Sys(Errno::EINVAL))
+				} else {
+					Ok(limit)
+				}
+			}
+		};
+		Ok(rlimit {
+			rlim_cur: to_raw(self.soft)?,
+			rlim_max: to_raw(self.hard)?,
+		})
+	}
+}
+
+/// Get the limits on `resource` for this process.
+pub fn get(resource: Resource) -> nix::Result<Rlimit> {
+	let mut rlim = mem::MaybeUninit::<rlimit>::uninit();
+	let err = unsafe { getrlimit(resource.as_raw(), rlim.as_mut_ptr()) };
+	Errno::result(err).map(|_| Rlimit::from_raw(unsafe { rlim.assume_init() }))
+}
+
+/// Set the limits on `resource` for this process. They're inherited by children. Finite limits too large to distinguish from `RLIM_INFINITY` on this platform fail with `EINVAL`.
+pub fn set(resource: Resource, limit: Rlimit) -> nix::Result<()> {
+	let rlim = mem::MaybeUninit::new(limit.to_raw()?);
+	let err = unsafe { setrlimit(resource.as_raw(), rlim.as_ptr()) };
+	Errno::result(err).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::convert::TryInto;
+
+	#[test]
+	fn get_set() {
+		// nothing else in the test harness depends on the core dump limit, and the hard limit is left alone as it can't be raised again
+		let limit = get(Resource::RLIMIT_CORE).unwrap();
+		let soft = limit.hard.map_or(1 << 20, |hard| hard / 2);
+		set(
+			Resource::RLIMIT_CORE,
+			Rlimit {
+				soft: Some(soft),
+				..limit
+			},
+		)
+		.unwrap();
+		assert_eq!(get(Resource::RLIMIT_CORE).unwrap().soft, Some(soft));
+		set(Resource::RLIMIT_CORE, limit).unwrap();
+		assert_eq!(get(Resource::RLIMIT_CORE).unwrap(), limit);
+		// the soft limit can't exceed the hard
+		assert_eq!(
+			set(
+				Resource::RLIMIT_CORE,
+				Rlimit {
+					soft: Some(soft + 1),
+					hard: Some(soft),
+				}
+			),
+			Err(Error::Sys(Errno::EINVAL))
+		);
+		assert_eq!(
+			set(
+				Resource::RLIMIT_CORE,
+				Rlimit {
+					soft: Some(INFINITY.try_into().unwrap()),
+					hard: None,
+				}
+			),
+			Err(Error::Sys(Errno::EINVAL))
+		);
+	}
+}
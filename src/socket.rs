@@ -3,9 +3,11 @@
 #[cfg(unix)]
 use super::*;
 #[cfg(unix)]
-use nix::{libc, poll, sys::socket};
+use nix::{errno::Errno, libc, poll, sys::socket, unistd, Error};
 #[cfg(unix)]
-use std::convert::TryInto;
+use std::{
+	convert::TryInto, mem::{self, size_of, size_of_val}, ptr, slice
+};
 
 #[doc(inline)]
 #[cfg(unix)]
@@ -182,3 +184,334 @@ pub fn unsent(fd: Fd) -> usize {
 	assert_eq!(err, 0);
 	unsent.try_into().unwrap()
 }
+
+/// The most fds [`send_fds`] can send, or [`recv_fds`] receive, in one message. This is Linux's `SCM_MAX_FD`.
+#[cfg(unix)]
+pub const MAX_FDS: usize = 253;
+
+/// Room for the control messages of [`MAX_FDS`] fds and credentials, aligned for `cmsghdr`.
+#[cfg(unix)]
+type ControlBuf = [u64; 160];
+
+/// A process's credentials, as passed by [`send_fds`] and [`recv_fds`].
+#[cfg(unix)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Credentials {
+	/// Process ID
+	pub pid: unistd::Pid,
+	/// Effective user ID
+	pub uid: unistd::Uid,
+	/// Effective group ID
+	pub gid: unistd::Gid,
+}
+
+/// What [`recv_fds`] received.
+#[cfg(unix)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Received {
+	/// Bytes received into `data`
+	pub len: usize,
+	/// Fds received into the start of `fds`
+	pub fds: usize,
+	/// The sender's credentials, if it sent them
+	pub credentials: Option<Credentials>,
+}
+
+/// Send `data` and `fds`, and if `credentials` our [`Credentials`], over the Unix stream or datagram socket `sock`. Returns how many bytes of `data` were sent; on a stream socket the fds and credentials go with the first of them, so sending them with empty `data` fails with `EINVAL`. At most [`MAX_FDS`] fds can be sent at once.
+///
+/// Sending to a peer that's closed its end fails with `EPIPE` rather than raising `SIGPIPE`, with `MSG_NOSIGNAL`, or on macOS and iOS by setting `SO_NOSIGPIPE` on `sock`.
+///
+/// Credentials are sent with `SCM_CREDENTIALS` on Linux and Android, where the receiver needs `SO_PASSCRED` set (with for example `nix::sys::socket::sockopt::PassCred`), and `SCM_CREDS` on FreeBSD, where the kernel fills them in. Elsewhere they fail with `EOPNOTSUPP`.
+#[cfg(unix)]
+#[allow(clippy::missing_panics_doc, clippy::useless_conversion)] // msghdr and cmsghdr field types vary by platform
+pub fn send_fds(sock: Fd, data: &[u8], fds: &[Fd], credentials: bool) -> nix::Result<usize> {
+	if fds.len() > MAX_FDS || ((!fds.is_empty() || credentials) && data.is_empty()) {
+		return Err(Error::Sys(Errno::EINVAL));
+	}
+	let mut buf: ControlBuf = [0; size_of::<ControlBuf>() / 8];
+	let mut used = 0;
+	let mut push = |ty: libc::c_int, data: &[u8]| {
+		let len: libc::c_uint = data.len().try_into().unwrap();
+		let space = unsafe { libc::CMSG_SPACE(len) } as usize;
+		assert!(used + space <= size_of_val(&buf));
+		#[allow(clippy::cast_ptr_alignment)]
+		unsafe {
+			let cmsg = buf
+				.as_mut_ptr()
+				.cast::<u8>()
+				.add(used)
+				.cast::<libc::cmsghdr>();
+			(*cmsg).cmsg_level = libc::SOL_SOCKET;
+			(*cmsg).cmsg_type = ty;
+			(*cmsg).cmsg_len = (libc::CMSG_LEN(len) as libc::size_t).try_into().unwrap();
+			ptr::copy_nonoverlapping(data.as_ptr(), libc::CMSG_DATA(cmsg), data.len());
+		}
+		used += space;
+	};
+	if !fds.is_empty() {
+		let fds = unsafe { slice::from_raw_parts(fds.as_ptr().cast::<u8>(), size_of_val(fds)) };
+		push(libc::SCM_RIGHTS, fds);
+	}
+	if credentials {
+		#[cfg(any(target_os = "android", target_os = "linux"))]
+		{
+			let ucred = libc::ucred {
+				pid: unistd::getpid().as_raw(),
+				uid: unistd::geteuid().as_raw(),
+				gid: unistd::getegid().as_raw(),
+			};
+			let ucred = unsafe {
+				slice::from_raw_parts(
+					slice::from_ref(&ucred).as_ptr().cast::<u8>(),
+					size_of_val(&ucred),
+				)
+			};
+			push(libc::SCM_CREDENTIALS, ucred);
+		}
+		#[cfg(target_os = "freebsd")]
+		push(libc::SCM_CREDS, &[0; size_of::<libc::cmsgcred>()]);
+		#[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "linux")))]
+		return Err(Error::Sys(Errno::EOPNOTSUPP));
+	}
+	let mut iov = [libc::iovec {
+		iov_base: data.as_ptr() as *mut libc::c_void,
+		iov_len: data.len(),
+	}];
+	let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+	msg.msg_iov = iov.as_mut_ptr();
+	msg.msg_iovlen = 1;
+	if used != 0 {
+		msg.msg_control = buf.as_mut_ptr().cast::<libc::c_void>();
+		msg.msg_controllen = used.try_into().unwrap();
+	}
+	// so a peer that's gone fails with `EPIPE` rather than killing us with `SIGPIPE`
+	#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux"))]
+	let flags = libc::MSG_NOSIGNAL;
+	#[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "linux")))]
+	let flags = 0;
+	#[cfg(any(target_os = "ios", target_os = "macos"))]
+	{
+		let on: libc::c_int = 1;
+		let ret = unsafe {
+			libc::setsockopt(
+				sock,
+				libc::SOL_SOCKET,
+				libc::SO_NOSIGPIPE,
+				slice::from_ref(&on).as_ptr().cast::<libc::c_void>(),
+				size_of_val(&on).try_into().unwrap(),
+			)
+		};
+		let _ = Errno::result(ret)?;
+	}
+	let ret = unsafe { libc::sendmsg(sock, slice::from_ref(&msg).as_ptr(), flags) };
+	Errno::result(ret).map(|len| len.try_into().unwrap())
+}
+
+/// Receive into `data`, and up to `fds.len()` fds (at most [`MAX_FDS`]) into `fds`, from the Unix stream or datagram socket `sock`, along with the sender's [`Credentials`] if sent. The fds are received CLOEXEC, atomically with `MSG_CMSG_CLOEXEC` where available.
+///
+/// If a datagram didn't fit in `data` (`MSG_TRUNC`), or more fds were sent than fit, or the control messages were otherwise truncated (`MSG_CTRUNC`), the fds that were received are closed and this fails with `EMSGSIZE`.
+#[cfg(unix)]
+#[allow(
+	clippy::missing_panics_doc,
+	clippy::too_many_lines,
+	clippy::useless_conversion
+)] // msghdr and cmsghdr field types vary by platform
+pub fn recv_fds(sock: Fd, data: &mut [u8], fds: &mut [Fd]) -> nix::Result<Received> {
+	let max_fds = fds.len().min(MAX_FDS);
+	let mut buf: ControlBuf = [0; size_of::<ControlBuf>() / 8];
+	let mut space = 0;
+	if max_fds != 0 {
+		let len = (max_fds * size_of::<Fd>()).try_into().unwrap();
+		space += unsafe { libc::CMSG_SPACE(len) } as usize;
+	}
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	let credentials_len = size_of::<libc::ucred>();
+	#[cfg(target_os = "freebsd")]
+	let credentials_len = size_of::<libc::cmsgcred>();
+	#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux"))]
+	{
+		space += unsafe { libc::CMSG_SPACE(credentials_len.try_into().unwrap()) } as usize;
+	}
+	assert!(space <= size_of_val(&buf));
+	let mut iov = [libc::iovec {
+		iov_base: data.as_mut_ptr().cast::<libc::c_void>(),
+		iov_len: data.len(),
+	}];
+	let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+	msg.msg_iov = iov.as_mut_ptr();
+	msg.msg_iovlen = 1;
+	if space != 0 {
+		msg.msg_control = buf.as_mut_ptr().cast::<libc::c_void>();
+		msg.msg_controllen = space.try_into().unwrap();
+	}
+	#[cfg(any(
+		target_os = "android",
+		target_os = "freebsd",
+		target_os = "linux",
+		target_os = "netbsd"
+	))]
+	let flags = libc::MSG_CMSG_CLOEXEC;
+	#[cfg(not(any(
+		target_os = "android",
+		target_os = "freebsd",
+		target_os = "linux",
+		target_os = "netbsd"
+	)))]
+	let flags = 0;
+	let msg_ptr = slice::from_mut(&mut msg).as_mut_ptr();
+	let len = Errno::result(unsafe { libc::recvmsg(sock, msg_ptr, flags) })?;
+	let mut received = Received {
+		len: len.try_into().unwrap(),
+		fds: 0,
+		credentials: None,
+	};
+	let mut truncated = msg.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0;
+	let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg_ptr) };
+	while !cmsg.is_null() {
+		let (level, ty, data, len) = unsafe {
+			let header_len = libc::CMSG_LEN(0) as usize;
+			let len: usize = (*cmsg).cmsg_len.try_into().unwrap();
+			(
+				(*cmsg).cmsg_level,
+				(*cmsg).cmsg_type,
+				libc::CMSG_DATA(cmsg),
+				len - header_len,
+			)
+		};
+		if level == libc::SOL_SOCKET && ty == libc::SCM_RIGHTS {
+			for i in 0..len / size_of::<Fd>() {
+				#[allow(clippy::cast_ptr_alignment)]
+				let fd = unsafe { ptr::read_unaligned(data.cast::<Fd>().add(i)) };
+				if received.fds < max_fds {
+					fds[received.fds] = fd;
+					received.fds += 1;
+				} else {
+					let _ = unistd::close(fd);
+					truncated = true;
+				}
+			}
+		}
+		#[cfg(any(target_os = "android", target_os = "linux"))]
+		{
+			if level == libc::SOL_SOCKET && ty == libc::SCM_CREDENTIALS && len >= credentials_len {
+				let ucred = unsafe { ptr::read_unaligned(data.cast::<libc::ucred>()) };
+				received.credentials = Some(Credentials {
+					pid: unistd::Pid::from_raw(ucred.pid),
+					uid: unistd::Uid::from_raw(ucred.uid),
+					gid: unistd::Gid::from_raw(ucred.gid),
+				});
+			}
+		}
+		#[cfg(target_os = "freebsd")]
+		{
+			if level == libc::SOL_SOCKET && ty == libc::SCM_CREDS && len >= credentials_len {
+				let cred = unsafe { ptr::read_unaligned(data.cast::<libc::cmsgcred>()) };
+				// the first group is the effective gid
+				received.credentials = Some(Credentials {
+					pid: unistd::Pid::from_raw(cred.cmcred_pid),
+					uid: unistd::Uid::from_raw(cred.cmcred_euid),
+					gid: unistd::Gid::from_raw(cred.cmcred_groups[0]),
+				});
+			}
+		}
+		cmsg = unsafe { libc::CMSG_NXTHDR(msg_ptr, cmsg) };
+	}
+	let fds = &fds[..received.fds];
+	if truncated {
+		for &fd in fds {
+			let _ = unistd::close(fd);
+		}
+		return Err(Error::Sys(Errno::EMSGSIZE));
+	}
+	#[cfg(not(any(
+		target_os = "android",
+		target_os = "freebsd",
+		target_os = "linux",
+		target_os = "netbsd"
+	)))]
+	{
+		use nix::fcntl;
+		for &fd in fds {
+			if let Err(err) = fcntl::fcntl(fd, fcntl::FcntlArg::F_SETFD(fcntl::FdFlag::FD_CLOEXEC))
+			{
+				for &fd in fds {
+					let _ = unistd::close(fd);
+				}
+				return Err(err);
+			}
+		}
+	}
+	Ok(received)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use nix::{fcntl, sys::signal};
+	use std::os::unix::{
+		io::AsRawFd, net::{UnixDatagram, UnixStream}
+	};
+
+	#[test]
+	fn send_recv_fds() {
+		let (a, b) = UnixDatagram::pair().unwrap();
+		let (read, write) = unistd::pipe().unwrap();
+		let sent = send_fds(a.as_raw_fd(), b"fds", &[read, write, write], false).unwrap();
+		assert_eq!(sent, 3);
+		let (mut data, mut fds) = ([0; 8], [-1; 4]);
+		let received = recv_fds(b.as_raw_fd(), &mut data, &mut fds).unwrap();
+		assert_eq!((&data[..received.len], received.fds), (&b"fds"[..], 3));
+		assert_eq!(received.credentials, None);
+		for &fd in &fds[..3] {
+			let flags = fcntl::fcntl(fd, fcntl::FcntlArg::F_GETFD).unwrap();
+			assert!(fcntl::FdFlag::from_bits_truncate(flags).contains(fcntl::FdFlag::FD_CLOEXEC));
+		}
+		assert_eq!(unistd::write(fds[1], &[1]), Ok(1));
+		assert_eq!(unistd::read(fds[0], &mut [0]), Ok(1));
+		for &fd in &[read, write, fds[0], fds[1], fds[2]] {
+			unistd::close(fd).unwrap();
+		}
+
+		// fds need data to go with
+		let err = send_fds(a.as_raw_fd(), b"", &[0], false);
+		assert_eq!(err, Err(Error::Sys(Errno::EINVAL)));
+
+		// more fds than fit
+		let _ = send_fds(a.as_raw_fd(), b"fds", &[0, 1, 2], false).unwrap();
+		let err = recv_fds(b.as_raw_fd(), &mut data, &mut fds[..1]);
+		assert_eq!(err, Err(Error::Sys(Errno::EMSGSIZE)));
+
+		// more data than fits
+		let _ = send_fds(a.as_raw_fd(), b"fds", &[0], false).unwrap();
+		let err = recv_fds(b.as_raw_fd(), &mut data[..2], &mut fds);
+		assert_eq!(err, Err(Error::Sys(Errno::EMSGSIZE)));
+
+		// to a peer that's gone, without raising SIGPIPE, which std ignores but by default would kill us
+		let (a, b) = UnixStream::pair().unwrap();
+		drop(b);
+		let old = unsafe { signal::signal(signal::SIGPIPE, signal::SigHandler::SigDfl) }.unwrap();
+		let err = send_fds(a.as_raw_fd(), b"fds", &[0], false);
+		let _ = unsafe { signal::signal(signal::SIGPIPE, old) }.unwrap();
+		assert_eq!(err, Err(Error::Sys(Errno::EPIPE)));
+	}
+
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	#[test]
+	fn credentials() {
+		let (a, b) = UnixStream::pair().unwrap();
+		socket::setsockopt(b.as_raw_fd(), socket::sockopt::PassCred, &true).unwrap();
+		let _ = send_fds(a.as_raw_fd(), b"creds", &[0], true).unwrap();
+		let (mut data, mut fds) = ([0; 8], [-1; 1]);
+		let received = recv_fds(b.as_raw_fd(), &mut data, &mut fds).unwrap();
+		assert_eq!((received.len, received.fds), (5, 1));
+		assert_eq!(
+			received.credentials,
+			Some(Credentials {
+				pid: unistd::getpid(),
+				uid: unistd::geteuid(),
+				gid: unistd::getegid(),
+			})
+		);
+		unistd::close(fds[0]).unwrap();
+	}
+}
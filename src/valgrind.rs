@@ -1,37 +1,8 @@
 //! Valgrind-related functionality
 
 use super::*;
-use nix::{errno, libc};
 use std::convert::TryInto;
 
-#[cfg(all(target_os = "linux", not(target_env = "musl")))]
-fn getrlimit(resource: libc::__rlimit_resource_t) -> nix::Result<libc::rlimit64> {
-	let mut rlim = libc::rlimit64 {
-		rlim_cur: 0,
-		rlim_max: 0,
-	};
-	let err = unsafe { libc::getrlimit64(resource, &mut rlim) };
-	errno::Errno::result(err).map(|_| rlim)
-}
-#[cfg(any(target_os = "android", target_env = "musl"))]
-fn getrlimit(resource: libc::c_int) -> nix::Result<libc::rlimit64> {
-	let mut rlim = libc::rlimit64 {
-		rlim_cur: 0,
-		rlim_max: 0,
-	};
-	let err = unsafe { libc::getrlimit64(resource, &mut rlim) };
-	errno::Errno::result(err).map(|_| rlim)
-}
-#[cfg(all(unix, not(any(target_os = "android", target_os = "linux"))))]
-fn getrlimit(resource: libc::c_int) -> nix::Result<libc::rlimit> {
-	let mut rlim = libc::rlimit {
-		rlim_cur: 0,
-		rlim_max: 0,
-	};
-	let err = unsafe { libc::getrlimit(resource, &mut rlim) };
-	errno::Errno::result(err).map(|_| rlim)
-}
-
 /// Check if we're running under valgrind
 pub fn is() -> Result<bool, ()> {
 	#[cfg(feature = "nightly")]
@@ -41,8 +12,8 @@ pub fn is() -> Result<bool, ()> {
 }
 /// Valgrind sets up various file descriptors for its purposes; they're all > any user fds, and this function gets the lowest of them
 pub fn start_fd() -> Fd {
-	let rlim = getrlimit(libc::RLIMIT_NOFILE).unwrap();
-	let valgrind_start_fd = rlim.rlim_max;
+	let rlim = rlimit::get(rlimit::Resource::RLIMIT_NOFILE).unwrap();
+	let valgrind_start_fd = rlim.hard.expect("RLIMIT_NOFILE is unlimited");
 	assert!(
 		valgrind_start_fd < Fd::max_value().try_into().unwrap(),
 		"{:?}",
@@ -1,16 +1,22 @@
 #[cfg(unix)]
 mod fork {
-	use nix::{sys::signal, unistd::Pid, *};
+	use nix::{poll, sys::signal, unistd::Pid, *};
 	use rand::{seq::SliceRandom, Rng};
 	use std::{
-		mem, process, sync::{
-			atomic::{AtomicBool, Ordering}, Arc
+		convert::TryInto, env, fs, mem, os::unix::{io::AsRawFd, net::UnixDatagram}, process, sync::{
+			atomic::{AtomicBool, AtomicUsize, Ordering}, Arc
 		}, thread::{self, sleep}, time::Duration
 	};
 
+	#[cfg(target_os = "linux")]
+	use palaver::process::{CommandExt, ForkServer, IdMap, Namespaces, FORK_SERVER_MAX_ARG};
 	use palaver::{
-		file::pipe, process::{fork, ForkResult}
+		file::pipe, process::{
+			atfork, daemonize, fork, fork_with, ChildHandle, DaemonOptions, ForkError, ForkOptions, ForkResult, PidFile, PidFileError, Reaper, Spawn, WaitEvent, WaitStatus
+		}, rlimit::{self, Resource, Rlimit}
 	};
+	#[cfg(target_os = "linux")]
+	use std::process::{Command, Stdio};
 
 	#[global_allocator]
 	static ALLOC: forbid_alloc::Alloc<std::alloc::System> =
@@ -154,12 +160,1248 @@ mod fork {
 		})
 	}
 
+	fn wait_timeout() {
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			loop {
+				unistd::pause()
+			}
+		};
+		assert!(child.try_wait().unwrap().is_none());
+		assert!(child
+			.wait_timeout(Duration::from_millis(10))
+			.unwrap()
+			.is_none());
+		child.signal(signal::SIGTERM).unwrap();
+		match child.wait_timeout(Duration::from_secs(60)).unwrap() {
+			Some(WaitStatus::Signaled(signal::SIGTERM, false)) => (),
+			status => panic!("{:?}", status),
+		}
+		match child.try_wait().unwrap() {
+			Some(WaitStatus::Signaled(signal::SIGTERM, false)) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_eq!(
+			child.signal(signal::SIGTERM).unwrap_err(),
+			nix::Error::Sys(nix::errno::Errno::ESRCH)
+		);
+	}
+
+	fn exit_fd() {
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			loop {
+				unistd::pause()
+			}
+		};
+		let fd = child.exit_fd().unwrap();
+		assert_eq!(child.as_raw_fd(), fd);
+		let mut fds = [poll::PollFd::new(fd, poll::PollFlags::POLLIN)];
+		assert_eq!(poll::poll(&mut fds, 10).unwrap(), 0);
+		child.signal(signal::SIGTERM).unwrap();
+		assert_eq!(poll::poll(&mut fds, -1).unwrap(), 1);
+		match child.try_wait().unwrap() {
+			Some(WaitStatus::Signaled(signal::SIGTERM, false)) => (),
+			status => panic!("{:?}", status),
+		}
+	}
+
+	fn orphan() {
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(true).unwrap() {
+			child
+		} else {
+			let pid = unistd::getpid().as_raw().to_ne_bytes();
+			let err = unistd::write(write, &pid).unwrap();
+			assert_eq!(err, pid.len());
+			process::exit(0);
+		};
+		unistd::close(write).unwrap();
+		let mut pid = [0; 4];
+		let err = unistd::read(read, &mut pid).unwrap();
+		assert_eq!(err, pid.len());
+		assert_eq!(child.pid, Pid::from_raw(i32::from_ne_bytes(pid)));
+		unistd::close(read).unwrap();
+		#[cfg(not(target_os = "freebsd"))]
+		assert_eq!(
+			child.exit_fd().unwrap_err().raw_os_error(),
+			Some(libc::EINVAL)
+		);
+	}
+
+	fn rusage() {
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let start = std::time::Instant::now();
+			while start.elapsed() < Duration::from_millis(100) {}
+			process::exit(0);
+		};
+		let (status, rusage) = child.wait_with_rusage().unwrap();
+		match status {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_ne!(rusage.user_time + rusage.system_time, Duration::new(0, 0));
+		assert_ne!(rusage.max_rss, 0);
+	}
+
+	fn wait_events() {
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			loop {
+				unistd::pause()
+			}
+		};
+		let mut events = child.wait_events();
+		child.signal(signal::SIGSTOP).unwrap();
+		match events.next().unwrap().unwrap() {
+			WaitEvent::Stopped(signal::SIGSTOP) => (),
+			event => panic!("{:?}", event),
+		}
+		child.signal(signal::SIGCONT).unwrap();
+		match events.next().unwrap().unwrap() {
+			WaitEvent::Continued => (),
+			event => panic!("{:?}", event),
+		}
+		// a blocked wait_events doesn't block other waiters
+		let child = Arc::new(child);
+		let waiter = {
+			let child = child.clone();
+			thread::spawn(move || child.wait_events().next().unwrap().unwrap())
+		};
+		thread::sleep(Duration::from_millis(100));
+		assert!(child.try_wait().unwrap().is_none());
+		child.signal(signal::SIGKILL).unwrap();
+		match waiter.join().unwrap() {
+			WaitEvent::Signaled(signal::SIGKILL, false) => (),
+			event => panic!("{:?}", event),
+		}
+		let mut events = child.wait_events();
+		match events.next().unwrap().unwrap() {
+			WaitEvent::Signaled(signal::SIGKILL, false) => (),
+			event => panic!("{:?}", event),
+		}
+		assert!(events.next().is_none());
+	}
+
+	fn multiple_waiters() {
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			loop {
+				unistd::pause()
+			}
+		};
+		let child = Arc::new(child);
+		let waiters = (0..4)
+			.map(|i| {
+				let child = child.clone();
+				thread::spawn(move || {
+					if i % 2 == 0 {
+						child.wait().unwrap()
+					} else {
+						child
+							.wait_timeout(Duration::from_secs(60))
+							.unwrap()
+							.unwrap()
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+		sleep(Duration::from_millis(10));
+		child.signal(signal::SIGTERM).unwrap();
+		for waiter in waiters {
+			match waiter.join().unwrap() {
+				WaitStatus::Signaled(signal::SIGTERM, false) => (),
+				status => panic!("{:?}", status),
+			}
+		}
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGTERM, false) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_eq!(
+			child.signal(signal::SIGTERM).unwrap_err(),
+			nix::Error::Sys(nix::errno::Errno::ESRCH)
+		);
+	}
+
+	fn signal_group() {
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0)).unwrap();
+			if let unistd::ForkResult::Child = unistd::fork().unwrap() {
+				let err = unistd::write(write, &[0]).unwrap();
+				assert_eq!(err, 1);
+			}
+			loop {
+				unistd::pause()
+			}
+		};
+		unistd::close(write).unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 1);
+		child.signal_group(signal::SIGTERM).unwrap();
+		// the grandchild, in the child's group, has died too
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 0);
+		unistd::close(read).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGTERM, false) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_eq!(
+			child.signal_group(signal::SIGTERM).unwrap_err(),
+			nix::Error::Sys(nix::errno::Errno::ESRCH)
+		);
+
+		// a child still in our group isn't signalled, nor are we
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			loop {
+				unistd::pause()
+			}
+		};
+		assert_eq!(
+			child.signal_group(signal::SIGTERM).unwrap_err(),
+			nix::Error::Sys(nix::errno::Errno::EPERM)
+		);
+		child.signal(signal::SIGKILL).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGKILL, false) => (),
+			status => panic!("{:?}", status),
+		}
+	}
+
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	fn sigqueue() {
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let mut set = mem::MaybeUninit::<libc::sigset_t>::uninit();
+			let set = unsafe {
+				assert_eq!(libc::sigemptyset(set.as_mut_ptr()), 0);
+				assert_eq!(libc::sigaddset(set.as_mut_ptr(), libc::SIGRTMIN()), 0);
+				set.assume_init()
+			};
+			let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+			assert_eq!(err, 0);
+			let err = unistd::write(write, &[0]).unwrap();
+			assert_eq!(err, 1);
+			let mut info = mem::MaybeUninit::<libc::siginfo_t>::uninit();
+			let signal = unsafe { libc::sigwaitinfo(&set, info.as_mut_ptr()) };
+			assert_eq!(signal, libc::SIGRTMIN());
+			let info = unsafe { info.assume_init() };
+			assert_eq!(info.si_code, libc::SI_QUEUE);
+			// sival_int, which libc doesn't expose, overlaps the start of sival_ptr
+			let (pid, value) = unsafe {
+				let value = info.si_value();
+				(
+					info.si_pid(),
+					*(&value as *const libc::sigval).cast::<i32>(),
+				)
+			};
+			assert_eq!(Pid::from_raw(pid), unistd::getppid());
+			let value = value.to_ne_bytes();
+			let err = unistd::write(write, &value).unwrap();
+			assert_eq!(err, 4);
+			process::exit(0);
+		};
+		unistd::close(write).unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 1);
+		child.sigqueue(libc::SIGRTMIN(), 1234).unwrap();
+		let mut value = [0; 4];
+		let err = unistd::read(read, &mut value).unwrap();
+		assert_eq!(err, 4);
+		assert_eq!(i32::from_ne_bytes(value), 1234);
+		unistd::close(read).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	fn namespaces() {
+		let options = ForkOptions {
+			namespaces: Namespaces::USER | Namespaces::PID | Namespaces::UTS,
+			uid_map: vec![IdMap {
+				inside: 0,
+				outside: unistd::getuid().as_raw(),
+				count: 1,
+			}],
+			gid_map: vec![IdMap {
+				inside: 0,
+				outside: unistd::getgid().as_raw(),
+				count: 1,
+			}],
+			..ForkOptions::default()
+		};
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let child = match fork_with(&options) {
+			Ok(ForkResult::Parent(child)) => child,
+			Ok(ForkResult::Child) => {
+				assert_eq!(unistd::getpid(), Pid::from_raw(1));
+				assert!(unistd::getuid().is_root());
+				assert_eq!(unistd::getgid().as_raw(), 0);
+				unistd::sethostname("palaver").unwrap();
+				let err = unistd::write(write, &[0]).unwrap();
+				assert_eq!(err, 1);
+				loop {
+					unistd::pause()
+				}
+			}
+			// user namespaces or pidfds may be unavailable
+			Err(ForkError::Namespace(_)) | Err(ForkError::Pidfd(errno::Errno::ENOSYS)) => {
+				unistd::close(read).unwrap();
+				unistd::close(write).unwrap();
+				return;
+			}
+			Err(err) => panic!("{}", err),
+		};
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 1);
+		// the pid is that in our namespace
+		assert_ne!(
+			std::fs::read_link(format!("/proc/{}/ns/pid", child.pid)).unwrap(),
+			std::fs::read_link("/proc/self/ns/pid").unwrap()
+		);
+		child.signal(signal::SIGKILL).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGKILL, _) => (),
+			status => panic!("{:?}", status),
+		}
+
+		// and it's killed on our death, despite being init of its pid namespace
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let _child = if let ForkResult::Parent(child) = fork_with(&options).unwrap() {
+				child
+			} else {
+				let err = unistd::write(write, &[0]).unwrap();
+				assert_eq!(err, 1);
+				loop {
+					unistd::pause()
+				}
+			};
+			unistd::close(write).unwrap();
+			loop {
+				unistd::pause()
+			}
+		};
+		unistd::close(write).unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 1);
+		signal::kill(child.pid, signal::SIGKILL).unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 0);
+		unistd::close(read).unwrap();
+	}
+
+	fn rlimits() {
+		let limit = rlimit::get(Resource::RLIMIT_NOFILE).unwrap();
+		let new = Rlimit {
+			soft: Some(64),
+			..limit
+		};
+		let options = ForkOptions {
+			rlimits: vec![(Resource::RLIMIT_NOFILE, new)],
+			..ForkOptions::default()
+		};
+		let child = if let ForkResult::Parent(child) = fork_with(&options).unwrap() {
+			child
+		} else {
+			let set = rlimit::get(Resource::RLIMIT_NOFILE).unwrap() == new;
+			process::exit(if set { 0 } else { 1 });
+		};
+		match child.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_eq!(rlimit::get(Resource::RLIMIT_NOFILE).unwrap(), limit);
+		// the soft limit can't exceed the hard
+		let options = ForkOptions {
+			rlimits: vec![(
+				Resource::RLIMIT_NOFILE,
+				Rlimit {
+					soft: None,
+					..limit
+				},
+			)],
+			..ForkOptions::default()
+		};
+		let err = fork_with(&options).unwrap_err();
+		assert_eq!(err, ForkError::Rlimit(errno::Errno::EINVAL));
+	}
+
+	fn daemon() {
+		let log = env::temp_dir().join(format!("palaver-daemon-{}.log", unistd::getpid()));
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let (other_read, other_write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let options = DaemonOptions {
+			stderr: Some(log.clone()),
+			keep_fds: vec![write],
+			..DaemonOptions::default()
+		};
+		// a blocked signal and a handled one, which the daemon resets
+		extern "C" fn handler(_: libc::c_int) {}
+		let mut mask = signal::SigSet::empty();
+		mask.add(signal::SIGUSR1);
+		let old_mask = mask
+			.thread_swap_mask(signal::SigmaskHow::SIG_BLOCK)
+			.unwrap();
+		let action = signal::SigAction::new(
+			signal::SigHandler::Handler(handler),
+			signal::SaFlags::empty(),
+			signal::SigSet::empty(),
+		);
+		let old_action = unsafe { signal::sigaction(signal::SIGUSR2, &action) }.unwrap();
+		let sid = unistd::getsid(None).unwrap();
+		let ret = daemonize(&options);
+		if let Ok(ForkResult::Parent(_)) | Err(_) = ret {
+			old_mask.thread_set_mask().unwrap();
+			let _ = unsafe { signal::sigaction(signal::SIGUSR2, &old_action) }.unwrap();
+		}
+		let child = if let ForkResult::Parent(child) = ret.unwrap() {
+			child
+		} else {
+			let sid_ = unistd::getsid(None).unwrap();
+			let reset = !signal::SigSet::thread_get_mask()
+				.unwrap()
+				.contains(signal::SIGUSR1)
+				&& unsafe { signal::sigaction(signal::SIGUSR2, &old_action) }
+					.unwrap()
+					.handler() == signal::SigHandler::SigDfl;
+			let detached = sid_ != sid
+				&& sid_ != unistd::getpid()
+				&& reset && env::current_dir().unwrap() == std::path::Path::new("/")
+				&& unistd::read(0, &mut [0]) == Ok(0)
+				&& fcntl::fcntl(other_read, fcntl::FcntlArg::F_GETFD).is_err()
+				&& fcntl::fcntl(other_write, fcntl::FcntlArg::F_GETFD).is_err();
+			eprintln!("started");
+			let _ = unistd::write(write, &[if detached { 1 } else { 0 }]).unwrap();
+			process::exit(0);
+		};
+		unistd::close(write).unwrap();
+		let mut msg = [0; 2];
+		assert_eq!(unistd::read(read, &mut msg).unwrap(), 1);
+		assert_eq!(msg[0], 1);
+		// the daemon's exited once it's closed its end
+		assert_eq!(unistd::read(read, &mut msg).unwrap(), 0);
+		unistd::close(read).unwrap();
+		unistd::close(other_read).unwrap();
+		unistd::close(other_write).unwrap();
+		assert_ne!(child.pid, unistd::getpid());
+		assert_eq!(fs::read_to_string(&log).unwrap(), "started\n");
+		fs::remove_file(&log).unwrap();
+		// failures to set up are reported back
+		let options = DaemonOptions {
+			dir: "/nonexistent".into(),
+			..DaemonOptions::default()
+		};
+		let err = daemonize(&options).unwrap_err();
+		assert_eq!(err, ForkError::Daemon(errno::Errno::ENOENT));
+	}
+
+	fn pid_file() {
+		let path = env::temp_dir().join(format!("palaver-fork-{}.pid", unistd::getpid()));
+		// a file that isn't locked is stale
+		fs::write(&path, "1\n").unwrap();
+		let (ready_read, ready_write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let (done_read, done_write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let mut pid_file = PidFile::create(&path).unwrap();
+		assert_eq!(pid_file.pid(), unistd::getpid());
+		assert_eq!(
+			fs::read_to_string(&path).unwrap(),
+			format!("{}\n", unistd::getpid())
+		);
+		let child = if let ForkResult::Parent(child) = fork(true).unwrap() {
+			child
+		} else {
+			unistd::close(ready_read).unwrap();
+			unistd::close(done_write).unwrap();
+			pid_file.update().unwrap();
+			let _ = unistd::write(ready_write, &[0]).unwrap();
+			assert_eq!(unistd::read(done_read, &mut [0]).unwrap(), 0);
+			drop(pid_file);
+			process::exit(0);
+		};
+		unistd::close(ready_write).unwrap();
+		unistd::close(done_read).unwrap();
+		assert_eq!(unistd::read(ready_read, &mut [0]).unwrap(), 1);
+		// the file holds the child's pid now, so isn't removed on our drop, after which the child alone holds the lock
+		drop(pid_file);
+		assert!(path.exists());
+		assert_eq!(
+			PidFile::create(&path).unwrap_err(),
+			PidFileError::Locked(Some(child.pid))
+		);
+		// the child removes the file on drop, then exits, closing its end
+		unistd::close(done_write).unwrap();
+		assert_eq!(unistd::read(ready_read, &mut [0]).unwrap(), 0);
+		unistd::close(ready_read).unwrap();
+		assert!(!path.exists());
+	}
+
+	fn reaper() {
+		let reaper = Reaper::new().unwrap();
+		assert_eq!(Reaper::new().unwrap_err(), Error::Sys(errno::Errno::EBUSY));
+		let mut pids = (0..10)
+			.map(|i| {
+				let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+					child
+				} else {
+					sleep(Duration::from_millis(10 * i));
+					process::exit(i.try_into().unwrap());
+				};
+				let pid = child.pid;
+				reaper.add(Arc::new(child));
+				pid
+			})
+			.collect::<Vec<_>>();
+		// orphaning leaves the handler in place
+		if let ForkResult::Child = fork(true).unwrap() {
+			process::exit(0);
+		}
+		let child = Arc::new(if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			process::exit(10);
+		});
+		reaper.add(child.clone());
+		pids.push(child.pid);
+		for (pid, status) in reaper.iter().take(pids.len()) {
+			let i = pids.iter().position(|&pid_| pid_ == pid).unwrap();
+			match status {
+				WaitStatus::Exited(code) if code == i.try_into().unwrap() => (),
+				status => panic!("{:?}", status),
+			}
+		}
+		// other references get the same status
+		match child.wait().unwrap() {
+			WaitStatus::Exited(10) => (),
+			status => panic!("{:?}", status),
+		}
+		assert!(reaper.try_reap().is_none());
+		drop(reaper);
+		drop(Reaper::new().unwrap());
+	}
+
+	fn atfork_handlers() {
+		static PREPARE: AtomicUsize = AtomicUsize::new(0);
+		static PARENT: AtomicUsize = AtomicUsize::new(0);
+		static CHILD: AtomicUsize = AtomicUsize::new(0);
+		atfork(
+			|| {
+				let _ = PREPARE.fetch_add(1, Ordering::SeqCst);
+			},
+			|| {
+				let _ = PARENT.fetch_add(1, Ordering::SeqCst);
+			},
+			|| {
+				let _ = CHILD.fetch_add(1, Ordering::SeqCst);
+			},
+		)
+		.unwrap();
+		// once in each process, however many helper processes are forked
+		let once = || {
+			PREPARE.load(Ordering::SeqCst) == PARENT.load(Ordering::SeqCst) + 1
+				&& CHILD.load(Ordering::SeqCst) == 1
+		};
+		for &orphan in &[false, true] {
+			let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+			let parent = PARENT.load(Ordering::SeqCst);
+			let _child = if let ForkResult::Parent(child) = fork(orphan).unwrap() {
+				child
+			} else {
+				let _ = unistd::write(write, &[if once() { 1 } else { 0 }]).unwrap();
+				process::exit(0);
+			};
+			unistd::close(write).unwrap();
+			let mut msg = [0];
+			assert_eq!(unistd::read(read, &mut msg).unwrap(), 1);
+			assert_eq!(msg[0], 1);
+			unistd::close(read).unwrap();
+			assert_eq!(PARENT.load(Ordering::SeqCst), parent + 1);
+			assert_eq!(PREPARE.load(Ordering::SeqCst), parent + 1);
+			assert_eq!(CHILD.load(Ordering::SeqCst), 0);
+		}
+		// and around other forks
+		let child = match unistd::fork().unwrap() {
+			unistd::ForkResult::Parent { child } => child,
+			unistd::ForkResult::Child => unsafe { libc::_exit(if once() { 0 } else { 1 }) },
+		};
+		assert_eq!(
+			sys::wait::waitpid(child, None).unwrap(),
+			sys::wait::WaitStatus::Exited(child, 0)
+		);
+		assert_eq!(PARENT.load(Ordering::SeqCst), 3);
+	}
+
+	#[cfg(target_os = "linux")]
+	fn fork_server() {
+		fn exit_with(arg: &[u8]) {
+			process::exit(arg[0].into());
+		}
+		fn pause(_arg: &[u8]) {
+			loop {
+				unistd::pause()
+			}
+		}
+		fn panics(_arg: &[u8]) {
+			panic!("entry panicked");
+		}
+		let server = match ForkServer::new(&[
+			("exit_with", exit_with),
+			("pause", pause),
+			("panics", panics),
+		]) {
+			Ok(server) => Arc::new(server),
+			// pidfds may be unavailable
+			Err(ForkError::Server(errno::Errno::ENOSYS)) => return,
+			Err(err) => panic!("{}", err),
+		};
+		// from many threads at once, with the children being ours to wait on
+		let threads = (0..8_u8)
+			.map(|i| {
+				let server = server.clone();
+				thread::spawn(move || {
+					let child = server.spawn("exit_with", &[i]).unwrap();
+					match child.wait().unwrap() {
+						WaitStatus::Exited(code) if code == i32::from(i) => (),
+						status => panic!("{:?}", status),
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+		let child = server.spawn("pause", &[]).unwrap();
+		child.signal(signal::SIGTERM).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGTERM, _) => (),
+			status => panic!("{:?}", status),
+		}
+		let child = server.spawn("panics", &[]).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Exited(101) => (),
+			status => panic!("{:?}", status),
+		}
+		// killed on drop
+		let child = server.spawn("pause", &[]).unwrap();
+		let pid = child.pid;
+		drop(child);
+		assert_eq!(
+			signal::kill(pid, None),
+			Err(Error::Sys(errno::Errno::ESRCH))
+		);
+		let child = server
+			.spawn("exit_with", &[7; FORK_SERVER_MAX_ARG])
+			.unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Exited(7) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_eq!(
+			server
+				.spawn("exit_with", &[7; FORK_SERVER_MAX_ARG + 1])
+				.unwrap_err(),
+			ForkError::Server(errno::Errno::EMSGSIZE)
+		);
+		assert_eq!(
+			server.spawn("missing", &[]).unwrap_err(),
+			ForkError::Server(errno::Errno::ENOENT)
+		);
+		drop(Arc::try_unwrap(server).unwrap());
+
+		// and the children are killed on our death, as is the server
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let server = ForkServer::new(&[("pause", pause)]).unwrap();
+			let _child = server.spawn("pause", &[]).unwrap();
+			let err = unistd::write(write, &[0]).unwrap();
+			assert_eq!(err, 1);
+			unistd::close(write).unwrap();
+			loop {
+				unistd::pause()
+			}
+		};
+		unistd::close(write).unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 1);
+		signal::kill(child.pid, signal::SIGKILL).unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 0);
+		unistd::close(read).unwrap();
+	}
+
+	fn spawn() {
+		// explicit environment, directory and fd mapping
+		let (read, write) = pipe(fcntl::OFlag::O_CLOEXEC).unwrap();
+		let child = Spawn::new("/bin/sh")
+			.args(&["-c", "echo $GREETING; pwd; echo ${FOO-unset} >&3"])
+			.env_clear()
+			.env("GREETING", "hello")
+			.env("FOO", "foo")
+			.env_remove("FOO")
+			.current_dir("/")
+			.fd(write, 1)
+			.fd(write, 3)
+			.spawn()
+			.unwrap();
+		unistd::close(write).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+		let mut output = [0; 16];
+		let len = unistd::read(read, &mut output).unwrap();
+		assert_eq!(&output[..len], b"hello\n/\nunset\n");
+		unistd::close(read).unwrap();
+
+		// the environment is ours as of spawning, with the changes applied on top
+		env::set_var("PALAVER_SPAWN_A", "a");
+		let (read, write) = pipe(fcntl::OFlag::O_CLOEXEC).unwrap();
+		let mut spawn = Spawn::new("/bin/sh");
+		let _ = spawn
+			.args(&[
+				"-c",
+				"echo ${PALAVER_SPAWN_A-unset} $PALAVER_SPAWN_B $PALAVER_SPAWN_C",
+			])
+			.env_remove("PALAVER_SPAWN_A")
+			.env("PALAVER_SPAWN_B", "b")
+			.fd(write, 1);
+		env::set_var("PALAVER_SPAWN_B", "ours");
+		env::set_var("PALAVER_SPAWN_C", "c");
+		let child = spawn.spawn().unwrap();
+		for key in &["PALAVER_SPAWN_A", "PALAVER_SPAWN_B", "PALAVER_SPAWN_C"] {
+			env::remove_var(key);
+		}
+		unistd::close(write).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+		let len = unistd::read(read, &mut output).unwrap();
+		assert_eq!(&output[..len], b"unset b c\n");
+		unistd::close(read).unwrap();
+
+		// an in-memory binary, in its own process group, with the signal mask reset
+		let program = fs::File::open("/bin/sh").unwrap();
+		let mut set = signal::SigSet::empty();
+		set.add(signal::SIGUSR1);
+		set.thread_block().unwrap();
+		let child = Spawn::from_fd(program.as_raw_fd())
+			.args(&["sh", "-c", "kill -USR1 $$; exit 0"])
+			.process_group(Pid::from_raw(0))
+			.spawn()
+			.unwrap();
+		set.thread_unblock().unwrap();
+		drop(program);
+		assert_eq!(unistd::getpgid(Some(child.pid)), Ok(child.pid));
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGUSR1, _) => (),
+			status => panic!("{:?}", status),
+		}
+
+		// exec failures are reported
+		assert_eq!(
+			Spawn::new("/nonexistent").spawn().unwrap_err(),
+			ForkError::Exec(errno::Errno::ENOENT)
+		);
+		assert_eq!(
+			Spawn::new("/bin/sh")
+				.current_dir("/nonexistent")
+				.spawn()
+				.unwrap_err(),
+			ForkError::Exec(errno::Errno::ENOENT)
+		);
+		assert_eq!(
+			Spawn::new("/bin/sh").arg("a\0b").spawn().unwrap_err(),
+			ForkError::Exec(errno::Errno::EINVAL)
+		);
+		// any number of args, which the child needn't allocate for
+		let child = Spawn::new("/bin/sh")
+			.args(&["-c", "exit $#", "sh"])
+			.args(vec!["a"; 300])
+			.spawn()
+			.unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Exited(44) => (),
+			status => panic!("{:?}", status),
+		}
+
+		// from another thread, which falls back to fork on Linux
+		thread::spawn(|| {
+			let child = Spawn::new("/bin/sh")
+				.args(&["-c", "exit 3"])
+				.spawn()
+				.unwrap();
+			match child.wait().unwrap() {
+				WaitStatus::Exited(3) => (),
+				status => panic!("{:?}", status),
+			}
+			assert_eq!(
+				Spawn::new("/nonexistent").spawn().unwrap_err(),
+				ForkError::Exec(errno::Errno::ENOENT)
+			);
+		})
+		.join()
+		.unwrap();
+
+		// and the child's killed on our death
+		let (read, write) = pipe(fcntl::OFlag::O_CLOEXEC).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let _child = Spawn::new("/bin/sh")
+				.args(&["-c", "echo ok >&3; exec sleep 100"])
+				.fd(write, 3)
+				.spawn()
+				.unwrap();
+			unistd::close(write).unwrap();
+			loop {
+				unistd::pause()
+			}
+		};
+		unistd::close(write).unwrap();
+		let mut output = [0; 3];
+		let len = unistd::read(read, &mut output).unwrap();
+		assert_eq!(&output[..len], b"ok\n");
+		signal::kill(child.pid, signal::SIGKILL).unwrap();
+		assert_eq!(unistd::read(read, &mut output), Ok(0));
+		unistd::close(read).unwrap();
+	}
+
+	#[cfg(target_os = "linux")]
+	fn command_ext() {
+		let (read, write) = pipe(fcntl::OFlag::O_CLOEXEC).unwrap();
+		let child = match Command::new("/bin/sh")
+			.args(&["-c", "echo ok >&4; exec sleep 100"])
+			.inherit_fds(&[(write, 4)])
+			.new_process_group()
+			.kill_on_parent_death()
+			.spawn_handle()
+		{
+			Ok(child) => child,
+			// pidfds may be unavailable
+			Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+				unistd::close(read).unwrap();
+				unistd::close(write).unwrap();
+				return;
+			}
+			Err(err) => panic!("{}", err),
+		};
+		unistd::close(write).unwrap();
+		let mut output = [0; 3];
+		let len = unistd::read(read, &mut output).unwrap();
+		assert_eq!(&output[..len], b"ok\n");
+		assert_eq!(unistd::getpgid(Some(child.pid)), Ok(child.pid));
+		child.signal(signal::SIGTERM).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGTERM, _) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_eq!(unistd::read(read, &mut output), Ok(0));
+		unistd::close(read).unwrap();
+		assert_eq!(
+			Command::new("/bin/sh")
+				.inherit_fds(&[(1000, 3)])
+				.spawn_handle()
+				.unwrap_err()
+				.raw_os_error(),
+			Some(libc::EBADF)
+		);
+		assert_eq!(
+			Command::new("/bin/true")
+				.stdout(Stdio::piped())
+				.spawn_handle()
+				.unwrap_err()
+				.raw_os_error(),
+			Some(libc::EINVAL)
+		);
+		// targets that aren't open are where std's exec error pipe would otherwise go
+		let free = (unistd::dup(0).unwrap(), unistd::dup(0).unwrap());
+		unistd::close(free.0).unwrap();
+		unistd::close(free.1).unwrap();
+		let mut command = Command::new("/nonexistent");
+		let _ = command.inherit_fds(&[(0, free.0), (0, free.1)]);
+		assert_eq!(
+			command.spawn().unwrap_err().raw_os_error(),
+			Some(libc::ENOENT)
+		);
+		drop(command);
+		assert_eq!(unistd::dup(0), Ok(free.0));
+		unistd::close(free.0).unwrap();
+
+		// and it's killed on our death
+		let (read, write) = pipe(fcntl::OFlag::O_CLOEXEC).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let _child = Command::new("/bin/sh")
+				.args(&["-c", "echo ok >&3; exec sleep 100"])
+				.inherit_fds(&[(write, 3)])
+				.kill_on_parent_death()
+				.spawn_handle()
+				.unwrap();
+			unistd::close(write).unwrap();
+			loop {
+				unistd::pause()
+			}
+		};
+		unistd::close(write).unwrap();
+		let mut output = [0; 3];
+		let len = unistd::read(read, &mut output).unwrap();
+		assert_eq!(&output[..len], b"ok\n");
+		signal::kill(child.pid, signal::SIGKILL).unwrap();
+		assert_eq!(unistd::read(read, &mut output), Ok(0));
+		unistd::close(read).unwrap();
+	}
+
+	fn fork_error() {
+		let mut limit = libc::rlimit {
+			rlim_cur: 0,
+			rlim_max: 0,
+		};
+		assert_eq!(
+			unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+			0
+		);
+		let lowest = unistd::dup(0).unwrap();
+		unistd::close(lowest).unwrap();
+		assert_eq!(
+			fcntl::fcntl(lowest + 1, fcntl::FcntlArg::F_GETFD),
+			Err(Error::Sys(errno::Errno::EBADF))
+		);
+		// room for the socket pair in the parent, but not for the pipe the child then needs
+		let new = libc::rlimit {
+			rlim_cur: (lowest + 2).try_into().unwrap(),
+			..limit
+		};
+		assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &new) }, 0);
+		// from another thread, as from the main thread on Linux the child needs no more fds
+		let err = thread::spawn(|| fork(false).unwrap_err()).join().unwrap();
+		assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+		assert_eq!(err, ForkError::Pipe(errno::Errno::EMFILE));
+	}
+
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	fn kill_tree() {
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			if let unistd::ForkResult::Child = unistd::fork().unwrap() {
+				let _ = unistd::setsid().unwrap();
+				if let unistd::ForkResult::Child = unistd::fork().unwrap() {
+					let err = unistd::write(write, &[0]).unwrap();
+					assert_eq!(err, 1);
+				}
+				loop {
+					unistd::pause()
+				}
+			}
+			loop {
+				unistd::pause()
+			}
+		};
+		unistd::close(write).unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 1);
+		child.kill_tree().unwrap();
+		let err = unistd::read(read, &mut [0]).unwrap();
+		assert_eq!(err, 0);
+		// we're no longer a subreaper
+		let mut subreaper: libc::c_int = 1;
+		assert_eq!(
+			unsafe { libc::prctl(libc::PR_GET_CHILD_SUBREAPER, &mut subreaper, 0, 0, 0) },
+			0
+		);
+		assert_eq!(subreaper, 0);
+		unistd::close(read).unwrap();
+		match child.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGKILL, false) => (),
+			status => panic!("{:?}", status),
+		}
+	}
+
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	fn subreaper() {
+		let subreaper = palaver::process::Subreaper::new().unwrap();
+		let (read, write) = pipe(fcntl::OFlag::empty()).unwrap();
+		let child = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			if let unistd::ForkResult::Parent { child } = unistd::fork().unwrap() {
+				let err = unistd::write(write, &child.as_raw().to_ne_bytes()).unwrap();
+				assert_eq!(err, 4);
+				process::exit(0);
+			}
+			sleep(Duration::from_millis(100));
+			process::exit(3);
+		};
+		unistd::close(write).unwrap();
+		let mut pid = [0; 4];
+		let err = unistd::read(read, &mut pid).unwrap();
+		assert_eq!(err, 4);
+		unistd::close(read).unwrap();
+		let grandchild = Pid::from_raw(i32::from_ne_bytes(pid));
+		// by now the child's probably exited, and any helper process of its may have been reparented to us and reaped
+		sleep(Duration::from_millis(10));
+		assert!(subreaper.try_iter().all(|orphan| {
+			let (pid, _) = orphan.unwrap();
+			pid != child.pid && pid != grandchild
+		}));
+		// blocks till the grandchild's exit wakes it, leaving the child, a zombie by now, to its handle
+		match subreaper
+			.iter()
+			.map(|orphan| orphan.unwrap())
+			.find(|&(pid, _)| pid == grandchild)
+			.unwrap()
+		{
+			(_, WaitStatus::Exited(3)) => (),
+			status => panic!("{:?}", status),
+		}
+		match child.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+	}
+
+	// after subreaper, so workers whose forker exits are reparented to us
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	fn transfer() {
+		let (ours, theirs) = UnixDatagram::pair().unwrap();
+		// a process forks a worker, sends us its handle, and exits
+		let hand_over = || {
+			let old = if let ForkResult::Parent(child) = fork(false).unwrap() {
+				child
+			} else {
+				let options = ForkOptions {
+					transferable: true,
+					..ForkOptions::default()
+				};
+				let worker = if let ForkResult::Parent(child) = fork_with(&options).unwrap() {
+					child
+				} else {
+					loop {
+						unistd::pause()
+					}
+				};
+				worker.send(theirs.as_raw_fd()).unwrap();
+				process::exit(0);
+			};
+			let worker = ChildHandle::receive(ours.as_raw_fd()).unwrap();
+			match old.wait().unwrap() {
+				WaitStatus::Exited(0) => (),
+				status => panic!("{:?}", status),
+			}
+			worker
+		};
+
+		// it outlives its forker, and having been reparented to us is ours to reap
+		let worker = hand_over();
+		worker.signal(None).unwrap();
+		assert!(worker.try_wait().unwrap().is_none());
+		worker.signal(signal::SIGTERM).unwrap();
+		match worker.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGTERM, _) => (),
+			status => panic!("{:?}", status),
+		}
+		assert_eq!(
+			worker.send(ours.as_raw_fd()).unwrap_err(),
+			nix::Error::Sys(nix::errno::Errno::ESRCH)
+		);
+
+		// passed on to a process that isn't its parent, which can signal it and see it exit
+		let worker = hand_over();
+		let new = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let worker = ChildHandle::receive(theirs.as_raw_fd()).unwrap();
+			assert!(worker.try_wait().unwrap().is_none());
+			worker.signal(signal::SIGTERM).unwrap();
+			assert_eq!(
+				worker.wait().unwrap_err(),
+				nix::Error::Sys(nix::errno::Errno::ECHILD)
+			);
+			assert_eq!(
+				worker.signal(None).unwrap_err(),
+				nix::Error::Sys(nix::errno::Errno::ESRCH)
+			);
+			process::exit(0);
+		};
+		worker.send(ours.as_raw_fd()).unwrap();
+		match worker.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGTERM, _) => (),
+			status => panic!("{:?}", status),
+		}
+		match new.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+
+		// where it's polled for, as there's no pidfd and it isn't the receiver's child, the polling stops when the handle's dropped, rather than when it's reaped
+		let worker = hand_over();
+		let new = if let ForkResult::Parent(child) = fork(false).unwrap() {
+			child
+		} else {
+			let worker = ChildHandle::receive(theirs.as_raw_fd()).unwrap();
+			let _ = worker.as_raw_fd();
+			drop(worker);
+			process::exit(0);
+		};
+		worker.send(ours.as_raw_fd()).unwrap();
+		match new.wait().unwrap() {
+			WaitStatus::Exited(0) => (),
+			status => panic!("{:?}", status),
+		}
+		match worker.wait().unwrap() {
+			WaitStatus::Signaled(signal::SIGKILL, _) => (),
+			status => panic!("{:?}", status),
+		}
+
+		// and is killed when the received handle's dropped
+		let worker = hand_over();
+		let pid = worker.pid;
+		drop(worker);
+		match sys::wait::waitpid(pid, None).unwrap() {
+			sys::wait::WaitStatus::Signaled(_, signal::SIGKILL, _) => (),
+			status => panic!("{:?}", status),
+		}
+
+		// a child in new namespaces is transferable too
+		#[cfg(target_os = "linux")]
+		{
+			let forker = if let ForkResult::Parent(child) = fork(false).unwrap() {
+				child
+			} else {
+				let options = ForkOptions {
+					namespaces: Namespaces::USER,
+					transferable: true,
+					..ForkOptions::default()
+				};
+				let worker = match fork_with(&options) {
+					Ok(ForkResult::Parent(child)) => child,
+					Ok(ForkResult::Child) => loop {
+						unistd::pause()
+					},
+					// user namespaces or pidfds may be unavailable
+					Err(_) => process::exit(1),
+				};
+				worker.send(theirs.as_raw_fd()).unwrap();
+				process::exit(0);
+			};
+			match forker.wait().unwrap() {
+				WaitStatus::Exited(0) => {
+					let worker = ChildHandle::receive(ours.as_raw_fd()).unwrap();
+					worker.signal(None).unwrap();
+					assert!(worker.try_wait().unwrap().is_none());
+					let pid = worker.pid;
+					drop(worker);
+					match sys::wait::waitpid(pid, None).unwrap() {
+						sys::wait::WaitStatus::Signaled(_, signal::SIGKILL, _) => (),
+						status => panic!("{:?}", status),
+					}
+				}
+				WaitStatus::Exited(1) => (),
+				status => panic!("{:?}", status),
+			}
+		}
+
+		let orphan = if let ForkResult::Parent(child) = fork(true).unwrap() {
+			child
+		} else {
+			process::exit(0);
+		};
+		assert_eq!(
+			orphan.send(ours.as_raw_fd()).unwrap_err(),
+			nix::Error::Sys(nix::errno::Errno::EINVAL)
+		);
+		theirs.send(b"hello").unwrap();
+		assert_eq!(
+			ChildHandle::receive(ours.as_raw_fd()).unwrap_err(),
+			nix::Error::Sys(nix::errno::Errno::EBADMSG)
+		);
+	}
+
 	// We need precisely 1 thread, so we can't use #[test]
 	pub fn main() {
 		println!("multithreaded");
 		multithreaded();
 		println!("group_kill");
 		group_kill();
+		println!("wait_timeout");
+		wait_timeout();
+		println!("exit_fd");
+		exit_fd();
+		println!("orphan");
+		orphan();
+		println!("rusage");
+		rusage();
+		println!("wait_events");
+		wait_events();
+		println!("multiple_waiters");
+		multiple_waiters();
+		println!("signal_group");
+		signal_group();
+		#[cfg(any(target_os = "android", target_os = "linux"))]
+		{
+			println!("sigqueue");
+			sigqueue();
+		}
+		#[cfg(target_os = "linux")]
+		{
+			println!("namespaces");
+			namespaces();
+		}
+		println!("rlimits");
+		rlimits();
+		println!("daemon");
+		daemon();
+		println!("pid_file");
+		pid_file();
+		println!("reaper");
+		reaper();
+		println!("atfork");
+		atfork_handlers();
+		#[cfg(target_os = "linux")]
+		{
+			println!("fork_server");
+			fork_server();
+		}
+		println!("spawn");
+		spawn();
+		#[cfg(target_os = "linux")]
+		{
+			println!("command_ext");
+			command_ext();
+		}
+		println!("fork_error");
+		fork_error();
+		#[cfg(any(target_os = "android", target_os = "linux"))]
+		{
+			println!("kill_tree");
+			kill_tree();
+			println!("subreaper");
+			subreaper();
+			println!("transfer");
+			transfer();
+		}
 		println!("done");
 	}
 